@@ -17,3 +17,137 @@ pub fn num2deg(xtile: u32, ytile: u32, zoom: u32) -> (f64, f64) {
     let lat_deg = lat_rad.to_degrees();
     return (lat_deg, lon_deg);
 }
+
+/// Like `num2deg`, for a point anywhere in a tile, e.g. (x + 0.5, y + 0.5) for
+/// its center.
+pub fn tile_point_to_deg(x: f64, y: f64, zoom: u32) -> (f64, f64) {
+    let n = 2.0_f64.powi(zoom as i32);
+    let lon_deg = x / n * 360.0 - 180.0;
+    let lat_rad = (std::f64::consts::PI * (1. - 2. * y / n)).sinh().atan();
+    (lat_rad.to_degrees(), lon_deg)
+}
+
+/// Equatorial circumference of the WGS84 ellipsoid.
+pub const EARTH_CIRCUMFERENCE: f64 = 40_075_016.686;
+
+/// Width on the ground of the tiles at a latitude, in meters.
+pub fn tile_width_meters(lat_deg: f64, zoom: u32) -> f64 {
+    EARTH_CIRCUMFERENCE * lat_deg.to_radians().cos() / 2.0_f64.powi(zoom as i32)
+}
+
+// Bing maps quadkeys, one base 4 digit per zoom level
+// From: https://docs.microsoft.com/en-us/bingmaps/articles/bing-maps-tile-system
+
+pub fn tile_to_quadkey(xtile: u32, ytile: u32, zoom: u32) -> String {
+    let mut quadkey = String::with_capacity(zoom as usize);
+    for i in (1..=zoom).rev() {
+        let mask = 1 << (i - 1);
+        let mut digit = 0;
+        if xtile & mask != 0 {
+            digit += 1;
+        }
+        if ytile & mask != 0 {
+            digit += 2;
+        }
+        quadkey.push(std::char::from_digit(digit, 4).unwrap());
+    }
+    quadkey
+}
+
+/// Returns None for quadkeys with other digits, or too long for the tile
+/// coordinates to fit.
+pub fn quadkey_to_tile(quadkey: &str) -> Option<(u32, u32, u32)> {
+    if quadkey.len() > 32 {
+        return None;
+    }
+    let zoom = quadkey.len() as u32;
+    let mut xtile = 0;
+    let mut ytile = 0;
+    for (i, c) in quadkey.chars().enumerate() {
+        let mask = 1 << (zoom - i as u32 - 1);
+        match c {
+            '0' => {}
+            '1' => xtile |= mask,
+            '2' => ytile |= mask,
+            '3' => {
+                xtile |= mask;
+                ytile |= mask;
+            }
+            _ => return None,
+        }
+    }
+    Some((xtile, ytile, zoom))
+}
+
+/// A bounding box in degrees, over a range of zoom levels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TileRegion {
+    pub west: f64,
+    pub south: f64,
+    pub east: f64,
+    pub north: f64,
+    pub min_zoom: u32,
+    pub max_zoom: u32,
+}
+
+impl TileRegion {
+    /// Parses `west,south,east,north,min_zoom,max_zoom`.
+    pub fn parse(value: &str) -> Option<TileRegion> {
+        let parts: Vec<&str> = value.split(',').map(|part| part.trim()).collect();
+        if parts.len() != 6 {
+            return None;
+        }
+        Some(TileRegion {
+            west: parts[0].parse().ok()?,
+            south: parts[1].parse().ok()?,
+            east: parts[2].parse().ok()?,
+            north: parts[3].parse().ok()?,
+            min_zoom: parts[4].parse().ok()?,
+            max_zoom: parts[5].parse().ok()?,
+        })
+    }
+
+    /// The first and last columns and rows of the region's tiles at a zoom level.
+    pub fn tile_range(&self, zoom: u32) -> ((u32, u32), (u32, u32)) {
+        let last_tile = 2_u32.pow(zoom) - 1;
+        let (min_x, min_y) = deg2num(self.north, self.west, zoom);
+        let (max_x, max_y) = deg2num(self.south, self.east, zoom);
+        (
+            (min_x.min(last_tile), min_y.min(last_tile)),
+            (max_x.min(last_tile), max_y.min(last_tile)),
+        )
+    }
+
+    pub fn contains(&self, x: u32, y: u32, z: u32) -> bool {
+        if z < self.min_zoom || z > self.max_zoom {
+            return false;
+        }
+        let ((min_x, min_y), (max_x, max_y)) = self.tile_range(z);
+        (min_x..=max_x).contains(&x) && (min_y..=max_y).contains(&y)
+    }
+}
+
+pub fn tile_ancestor(xtile: u32, ytile: u32, zoom: u32, levels_up: u32) -> (u32, u32, u32) {
+    (xtile >> levels_up, ytile >> levels_up, zoom - levels_up)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quadkey_round_trip() {
+        assert_eq!(tile_to_quadkey(3, 5, 3), "213");
+        assert_eq!(quadkey_to_tile("213"), Some((3, 5, 3)));
+        assert_eq!(quadkey_to_tile(""), Some((0, 0, 0)));
+        let last_tile = u32::MAX;
+        let quadkey = tile_to_quadkey(last_tile, 0, 32);
+        assert_eq!(quadkey_to_tile(&quadkey), Some((last_tile, 0, 32)));
+    }
+
+    #[test]
+    fn quadkey_too_long() {
+        assert_eq!(quadkey_to_tile(&"1".repeat(33)), None);
+        assert_eq!(quadkey_to_tile("1a"), None);
+    }
+}
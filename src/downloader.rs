@@ -0,0 +1,321 @@
+use super::map_services::TileError;
+use super::tile_cache::{unix_time, TileMetadata};
+use reqwest::header::{
+    HeaderMap, HeaderName, CACHE_CONTROL, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+    RETRY_AFTER,
+};
+use reqwest::{Response, StatusCode};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Semaphore;
+
+/// Limits applied to the requests made to each tile server.
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// Requests to the same host that may be in flight at once.
+    pub max_connections_per_host: usize,
+    /// Sustained request rate per host.
+    pub requests_per_second: f64,
+    /// Requests that may be sent at once after the host has been idle.
+    pub burst: u32,
+    /// Attempts after the first one for throttled requests and server errors.
+    pub max_retries: u32,
+    /// Wait before the first retry, doubled for each following one.
+    pub initial_backoff: Duration,
+    /// Longest wait between two attempts, including `Retry-After` values.
+    pub max_backoff: Duration,
+    /// Fails every download without touching the network.
+    pub offline: bool,
+    /// Longest a single request may take, it's retried past it.
+    pub timeout: Duration,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> DownloadOptions {
+        DownloadOptions {
+            max_connections_per_host: 6,
+            requests_per_second: 20.,
+            burst: 10,
+            max_retries: 4,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            offline: false,
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Token bucket, refilled continuously at the configured rate.
+struct RateLimiter {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+struct Host {
+    connections: Semaphore,
+    rate_limiter: Mutex<RateLimiter>,
+}
+
+/// The outcome of a download.
+pub enum Download {
+    Modified(Vec<u8>, TileMetadata),
+    /// The copy we had is still current, the metadata is refreshed.
+    NotModified(TileMetadata),
+}
+
+/// Downloads tiles through one pooled HTTP client, throttling the requests
+/// made to each host so that servers don't start rejecting them.
+pub struct Downloader {
+    client: reqwest::Client,
+    options: DownloadOptions,
+    hosts: Mutex<HashMap<String, Arc<Host>>>,
+}
+
+impl Default for Downloader {
+    fn default() -> Downloader {
+        Downloader::new(DownloadOptions::default())
+    }
+}
+
+impl Downloader {
+    pub fn new(options: DownloadOptions) -> Downloader {
+        Downloader {
+            client: reqwest::Client::builder()
+                .timeout(options.timeout)
+                .build()
+                .expect("Failed to create the HTTP client"),
+            options,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.options.offline
+    }
+
+    fn host(&self, url: &str) -> Arc<Host> {
+        let host_name = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(|host| host.to_string()))
+            .unwrap_or_default();
+        let mut hosts = self.hosts.lock().unwrap();
+        hosts
+            .entry(host_name)
+            .or_insert_with(|| {
+                Arc::new(Host {
+                    connections: Semaphore::new(self.options.max_connections_per_host.max(1)),
+                    rate_limiter: Mutex::new(RateLimiter {
+                        tokens: self.options.burst as f64,
+                        last_refill: Instant::now(),
+                    }),
+                })
+            })
+            .clone()
+    }
+
+    /// Waits until the host's bucket has a token and takes it.
+    async fn wait_for_token(&self, host: &Host) {
+        let rate = self.options.requests_per_second;
+        if rate <= 0. {
+            return;
+        }
+        loop {
+            let wait = {
+                let mut limiter = host.rate_limiter.lock().unwrap();
+                let now = Instant::now();
+                let elapsed = now.duration_since(limiter.last_refill).as_secs_f64();
+                limiter.tokens =
+                    (limiter.tokens + elapsed * rate).min(self.options.burst.max(1) as f64);
+                limiter.last_refill = now;
+                if limiter.tokens >= 1. {
+                    limiter.tokens -= 1.;
+                    return;
+                }
+                Duration::from_secs_f64((1. - limiter.tokens) / rate)
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = self.options.initial_backoff * 2_u32.saturating_pow(attempt);
+        backoff.min(self.options.max_backoff)
+    }
+
+    /// Downloads a file, retrying when the server is throttling us or failing.
+    /// With the metadata of a cached copy, the file is only sent again if it
+    /// changed since.
+    pub async fn download(
+        &self,
+        url: &str,
+        cached: Option<&TileMetadata>,
+    ) -> Result<Download, TileError> {
+        if self.options.offline {
+            return Err(TileError::Offline);
+        }
+        let host = self.host(url);
+        let mut attempt = 0;
+        loop {
+            // Held until the body is read, so that the limit is on the
+            // transfers and not only on the requests
+            let connection = host
+                .connections
+                .acquire()
+                .await
+                .map_err(|_| TileError::Cancelled)?;
+            self.wait_for_token(&host).await;
+            let mut request = self.client.get(url);
+            if let Some(etag) = cached.and_then(|metadata| metadata.etag.as_ref()) {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(date) = cached.and_then(|metadata| metadata.last_modified.as_ref()) {
+                request = request.header(IF_MODIFIED_SINCE, date);
+            }
+            let result = request.send().await;
+            let retryable = match &result {
+                Ok(resp) => is_retryable(resp.status()),
+                Err(error) => error.is_timeout() || error.is_connect(),
+            };
+            if !retryable || attempt >= self.options.max_retries {
+                return read_response(result?, cached).await;
+            }
+            drop(connection);
+            let retry_wait = match &result {
+                Ok(resp) => retry_after(resp.headers()).unwrap_or_else(|| self.backoff(attempt)),
+                Err(_) => self.backoff(attempt),
+            };
+            attempt += 1;
+            tokio::time::sleep(retry_wait.min(self.options.max_backoff)).await;
+        }
+    }
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Reads a `Retry-After` header, given either in seconds or as the date to
+/// retry at. Callers cap the wait at `max_backoff`.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    // A date already past means retrying right away
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// Reads the validators and the max age of a response. A 304 response may
+/// leave some of them out, those are kept from the cached copy.
+fn response_metadata(headers: &HeaderMap, cached: Option<&TileMetadata>) -> TileMetadata {
+    let header = |name: HeaderName| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string())
+    };
+    let mut metadata = cached.cloned().unwrap_or_default();
+    metadata.fetched_at = unix_time(SystemTime::now());
+    if let Some(etag) = header(ETAG) {
+        metadata.etag = Some(etag);
+    }
+    if let Some(date) = header(LAST_MODIFIED) {
+        metadata.last_modified = Some(date);
+    }
+    if let Some(cache_control) = header(CACHE_CONTROL) {
+        metadata.max_age = parse_max_age(&cache_control);
+    }
+    metadata
+}
+
+/// Longer max ages are cut to it, tiles are revalidated at least once a year.
+const MAX_MAX_AGE: u64 = 365 * 24 * 60 * 60;
+
+/// Reads `max-age` from a `Cache-Control` header, `no-cache` and `no-store`
+/// meaning that the tile is stale right away.
+fn parse_max_age(cache_control: &str) -> Option<u64> {
+    let mut max_age = None;
+    for directive in cache_control.split(',').map(|directive| directive.trim()) {
+        let directive = directive.to_ascii_lowercase();
+        if directive == "no-cache" || directive == "no-store" {
+            return Some(0);
+        }
+        if let Some(seconds) = directive.strip_prefix("max-age=") {
+            max_age = seconds
+                .trim_matches('"')
+                .parse()
+                .ok()
+                .map(|seconds: u64| seconds.min(MAX_MAX_AGE));
+        }
+    }
+    max_age
+}
+
+async fn read_response(
+    resp: Response,
+    cached: Option<&TileMetadata>,
+) -> Result<Download, TileError> {
+    match resp.status() {
+        StatusCode::NOT_MODIFIED if cached.is_some() => Ok(Download::NotModified(
+            response_metadata(resp.headers(), cached),
+        )),
+        StatusCode::NOT_FOUND | StatusCode::NO_CONTENT => Err(TileError::NotFound),
+        status if !status.is_success() => Err(TileError::HttpStatus(status)),
+        _ => {
+            let metadata = response_metadata(resp.headers(), None);
+            Ok(Download::Modified(resp.bytes().await?.to_vec(), metadata))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn retry_after_header(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn retry_after_seconds() {
+        assert_eq!(
+            retry_after(&retry_after_header("120")),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            retry_after(&retry_after_header(" 0 ")),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+        assert_eq!(retry_after(&retry_after_header("soon")), None);
+    }
+
+    #[test]
+    fn retry_after_date() {
+        let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(60));
+        let wait = retry_after(&retry_after_header(&date)).unwrap();
+        assert!(wait > Duration::from_secs(55) && wait <= Duration::from_secs(60));
+
+        let past = retry_after_header("Wed, 21 Oct 2015 07:28:00 GMT");
+        assert_eq!(retry_after(&past), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn max_age() {
+        assert_eq!(parse_max_age("public, max-age=3600"), Some(3600));
+        assert_eq!(parse_max_age("Max-Age=\"60\""), Some(60));
+        assert_eq!(parse_max_age("max-age=3600, no-cache"), Some(0));
+        assert_eq!(parse_max_age("no-store"), Some(0));
+        assert_eq!(parse_max_age("public"), None);
+        assert_eq!(parse_max_age("max-age=soon"), None);
+        assert_eq!(parse_max_age("max-age=99999999999999"), Some(MAX_MAX_AGE));
+    }
+}
@@ -1,6 +1,7 @@
 use bevy::{
+    ecs::system::SystemParam,
     prelude::*,
-    render::camera::*,
+    render::{camera::*, texture::ImageType},
     tasks::{AsyncComputeTaskPool, Task},
 };
 
@@ -8,11 +9,22 @@ use futures_lite::future;
 
 mod camera_utils;
 mod coord_utils;
+mod downloader;
+mod heightmap;
 use camera_utils::*;
+use heightmap::*;
 mod map_services;
 use map_services::*;
+mod mbtiles;
+mod pmtiles;
+mod tile_cache;
+mod tile_requests;
+
+#[cfg(test)]
+mod mock_tile_server;
 
 fn main() {
+    let (globe_options, source_args) = parse_args();
     App::build()
         .insert_resource(Msaa { samples: 4 })
         .insert_resource(WindowDescriptor {
@@ -20,6 +32,8 @@ fn main() {
             ..Default::default()
         })
         .insert_resource(UserPosition::default())
+        .insert_resource(TileSources::from_arg_list(source_args))
+        .insert_resource(globe_options)
         .add_plugins(DefaultPlugins)
         .insert_resource(ClearColor(Color::rgb(0., 0., 0.)))
         .add_event::<MouseEvents>()
@@ -34,9 +48,58 @@ fn main() {
 const MAX_DIST: f32 = 20000.;
 const GLOBE_RADIUS: f32 = 6000.;
 const DIST_BUFFER: f32 = 2.;
+/// Mean radius of the Earth in meters, for the scale of the elevations.
+const EARTH_RADIUS: f32 = 6_371_008.8;
+/// Vertices along each side of a tile with elevation, those without are
+/// smooth enough with fewer.
+const ELEVATION_VERTICES: u32 = 33;
+const SPHERE_VERTICES: u32 = 8;
+/// How far behind the camera the light follows it.
+const LIGHT_DISTANCE: f32 = 60000.;
+
+struct GlobeOptions {
+    /// How many times higher than on the Earth's scale the terrain is shown.
+    exaggeration: f32,
+}
+
+/// Splits the globe's arguments from the ones for `TileSources`:
+/// - `--exaggeration <factor>` scales the elevations, 1 by default
+fn parse_args() -> (GlobeOptions, Vec<String>) {
+    let mut options = GlobeOptions { exaggeration: 1. };
+    let mut source_args = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--exaggeration" => {
+                let value = args
+                    .next()
+                    .unwrap_or_else(|| panic!("Missing value for {}", arg));
+                options.exaggeration = parse_arg(&arg, &value);
+            }
+            _ => source_args.push(arg),
+        }
+    }
+    (options, source_args)
+}
 
 struct GlobeTile;
 
+/// Marks the task fetching a tile's image and elevation.
+#[derive(PartialEq)]
+struct PendingTile {
+    x: u32,
+    y: u32,
+    z: u32,
+}
+
+struct TileData {
+    x: u32,
+    y: u32,
+    z: u32,
+    image: Result<TileContents, TileError>,
+    topo: Result<TileContents, TileError>,
+}
+
 #[derive(Default)]
 struct UserPosition {
     tile_x: u32,
@@ -44,7 +107,7 @@ struct UserPosition {
     zoom: u32,
 }
 
-fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
+fn setup(mut commands: Commands) {
     let mut camera_transform = Transform::from_translation(Vec3::ZERO);
     camera_transform.look_at(Vec3::new(0., 0., 0.), Vec3::Y);
 
@@ -61,53 +124,117 @@ fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
             x: 180.,
             y: 30.,
             zoom: MAX_DIST,
+        })
+        .with_children(|camera| {
+            // Far behind the camera, so that it lights the globe about as
+            // much whatever the zoom, and from above left to show the relief
+            let light_position = Vec3::new(-0.5, 0.5, 1.) * LIGHT_DISTANCE;
+            camera.spawn_bundle(LightBundle {
+                transform: Transform::from_translation(light_position),
+                light: Light {
+                    // The light fades with the square of the distance, this
+                    // lights the globe facing it about fully
+                    intensity: light_position.length_squared() * 5.,
+                    range: LIGHT_DISTANCE * 4.,
+                    ..Default::default()
+                },
+                ..Default::default()
+            });
         });
+}
 
-    commands.spawn_bundle(LightBundle {
-        transform: Transform::from_translation(Vec3::new(100., 0., 0.)),
-        light: Light {
-            intensity: 15000.0,
-            range: 200.0,
-            ..Default::default()
-        },
-        ..Default::default()
-    });
-
-    asset_server.watch_for_changes().unwrap();
+/// The assets the tiles' meshes are made of.
+#[derive(SystemParam)]
+struct TileAssets<'a> {
+    meshes: ResMut<'a, Assets<Mesh>>,
+    materials: ResMut<'a, Assets<StandardMaterial>>,
+    textures: ResMut<'a, Assets<Texture>>,
 }
 
 fn generate_tile(
-    x: u32,
-    y: u32,
-    z: u32,
+    tile_data: TileData,
     commands: &mut Commands,
-    meshes: &mut ResMut<Assets<Mesh>>,
-    materials: &mut ResMut<Assets<StandardMaterial>>,
-    asset_server: &Res<AssetServer>,
+    assets: &mut TileAssets,
+    tile_sources: &Res<TileSources>,
+    globe_options: &Res<GlobeOptions>,
 ) {
-    let path_str = format!("images/imagery_{}_{}_{}.jpeg", 2_u32.pow(z) - x - 1, y, z);
-    let path = std::path::Path::new(&path_str);
-    let texture_handle: Handle<Texture> = asset_server.load(path);
+    let image_type = ImageType::Extension(tile_sources.imagery.file_extension());
+    let texture = tile_data.image.and_then(|contents| {
+        Texture::from_buffer(&contents.data, image_type)
+            .map(|texture| (texture, contents))
+            .map_err(|error| TileError::Decode(error.to_string()))
+    });
+    let (texture_handle, contents) = match texture {
+        Ok((texture, contents)) => (Some(assets.textures.add(texture)), Some(contents)),
+        Err(error) => {
+            println!(
+                "No imagery for tile ({}, {}, {}): {}",
+                tile_data.x, tile_data.y, tile_data.z, error
+            );
+            (None, None)
+        }
+    };
 
-    let material = materials.add(StandardMaterial {
+    let heightmap_format = tile_sources.elevation.heightmap_format();
+    let elevation = tile_data.topo.and_then(|topo| {
+        decode_heightmap(heightmap_format, &topo).map(|heightmap| (heightmap, topo))
+    });
+    let elevation = match elevation {
+        Ok(elevation) => Some(elevation),
+        Err(error) => {
+            if !matches!(error, TileError::NotFound) {
+                println!(
+                    "No elevation for tile ({}, {}, {}): {}",
+                    tile_data.x, tile_data.y, tile_data.z, error
+                );
+            }
+            None
+        }
+    };
+
+    let material = assets.materials.add(StandardMaterial {
         roughness: 1.,
         metallic: 0.,
         // base_color: Color::rgb((x % 2) as f32, (y % 2) as f32, z as f32 / 13.),
-        base_color_texture: Some(texture_handle),
-        unlit: true,
+        base_color_texture: texture_handle,
+        reflectance: 0.,
         ..Default::default()
     });
     commands
         .spawn_bundle(PbrBundle {
-            mesh: meshes.add(tile_mesh(x, y, z)),
+            mesh: assets.meshes.add(tile_mesh(
+                tile_data.x,
+                tile_data.y,
+                tile_data.z,
+                contents.as_ref(),
+                elevation
+                    .as_ref()
+                    .map(|(heightmap, topo)| (heightmap, topo)),
+                globe_options.exaggeration,
+            )),
             material: material,
             ..Default::default()
         })
         .insert(GlobeTile);
 }
 
-fn tile_mesh(x: u32, y: u32, z: u32) -> Mesh {
-    let n_vertices = 8;
+/// With `contents` from an ancestor tile, the texture coordinates are mapped to
+/// the part of it covering this tile. With `elevation`, the vertices are
+/// raised from the sphere by their height, times `exaggeration`.
+fn tile_mesh(
+    x: u32,
+    y: u32,
+    z: u32,
+    contents: Option<&TileContents>,
+    elevation: Option<(&Heightmap, &TileContents)>,
+    exaggeration: f32,
+) -> Mesh {
+    let n_vertices = if elevation.is_some() {
+        ELEVATION_VERTICES
+    } else {
+        SPHERE_VERTICES
+    };
+    let scene_per_meter = GLOBE_RADIUS / EARTH_RADIUS * exaggeration;
 
     let n = 2_u32.pow(z);
     let theta = std::f32::consts::TAU / n as f32;
@@ -117,21 +244,29 @@ fn tile_mesh(x: u32, y: u32, z: u32) -> Mesh {
     let alpha_inc = alpha / (n_vertices - 1) as f32;
 
     let mut positions = Vec::new();
-    let mut normals = Vec::new();
     let mut uvs = Vec::new();
 
     for h in 0..n_vertices {
-        let r = (alpha_inc * h as f32 + y as f32 * alpha).sin() * GLOBE_RADIUS;
-        let vy = (alpha_inc * h as f32 + y as f32 * alpha).cos() * GLOBE_RADIUS;
+        let polar_angle = alpha_inc * h as f32 + y as f32 * alpha;
         for w in 0..n_vertices {
+            let uv = [
+                1. - w as f32 / (n_vertices - 1) as f32,
+                h as f32 / (n_vertices - 1) as f32,
+            ];
+            // Heights without data are taken as sea level
+            let height = elevation.map_or(0., |(heightmap, topo)| {
+                let [u, v] = topo.uv(uv);
+                Some(heightmap.sample(u, v))
+                    .filter(|height| !height.is_nan())
+                    .unwrap_or(0.)
+            });
+            let radius = GLOBE_RADIUS + height * scene_per_meter;
+            let r = polar_angle.sin() * radius;
+            let vy = polar_angle.cos() * radius;
             let vx = (theta_inc * w as f32 + x as f32 * theta).cos() * r;
             let vz = (theta_inc * w as f32 + x as f32 * theta).sin() * r;
             positions.push([vx, vy, vz]);
-            normals.push([0., 0., 0.]);
-            uvs.push([
-                1. - w as f32 / (n_vertices - 1) as f32,
-                h as f32 / (n_vertices - 1) as f32,
-            ]);
+            uvs.push(contents.map_or(uv, |contents| contents.uv(uv)));
         }
     }
 
@@ -148,6 +283,32 @@ fn tile_mesh(x: u32, y: u32, z: u32) -> Mesh {
         }
     }
 
+    // The normals point away from the centre, or with elevation, they're
+    // the average of the raised triangles around each vertex, which are
+    // counterclockwise seen from outside. The triangles at the poles have
+    // two vertices in the same place and don't count.
+    let mut face_normals = vec![Vec3::ZERO; positions.len()];
+    if elevation.is_some() {
+        for triangle in indices_vec.chunks_exact(3) {
+            let position = |i: usize| Vec3::from(positions[triangle[i] as usize]);
+            let normal = (position(1) - position(0)).cross(position(2) - position(0));
+            for index in triangle {
+                face_normals[*index as usize] += normal;
+            }
+        }
+    }
+    let normals: Vec<[f32; 3]> = face_normals
+        .into_iter()
+        .zip(&positions)
+        .map(|(normal, position)| {
+            if normal.length_squared() > 0. {
+                normal.normalize().into()
+            } else {
+                Vec3::from(*position).normalize().into()
+            }
+        })
+        .collect();
+
     let indices = bevy::render::mesh::Indices::U32(indices_vec);
 
     let mut mesh = Mesh::new(bevy::render::pipeline::PrimitiveTopology::TriangleList);
@@ -162,12 +323,11 @@ fn tile_mesh(x: u32, y: u32, z: u32) -> Mesh {
 fn on_zoom_updated(
     query: Query<&OrbitCamera, Changed<OrbitCamera>>,
     current_tiles: Query<Entity, With<GlobeTile>>,
+    pending_tiles: Query<(Entity, &PendingTile)>,
     mut res: ResMut<UserPosition>,
     mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-    asset_server: Res<AssetServer>,
     thread_pool: Res<AsyncComputeTaskPool>,
+    tile_sources: Res<TileSources>,
 ) {
     if let Ok(camera) = query.single() {
         let camera_dist = camera.zoom;
@@ -199,24 +359,31 @@ fn on_zoom_updated(
             let radius_x = 2;
             let radius_y = 2;
 
+            let mut wanted_tiles = Vec::new();
             for r_x in -radius_x..(radius_x + 1) {
                 for r_y in -radius_y..(radius_y + 1) {
                     let x = (new_tile_x as i32 + r_x).rem_euclid(2_i32.pow(new_zoom));
                     let y = (new_tile_y as i32 + r_y).rem_euclid(2_i32.pow(new_zoom));
+                    wanted_tiles.push(PendingTile {
+                        x: x as u32,
+                        y: y as u32,
+                        z: new_zoom,
+                    });
+                }
+            }
 
-                    request_tile(x as u32, y as u32, new_zoom, &mut commands, &thread_pool);
-
-                    generate_tile(
-                        x as u32,
-                        y as u32,
-                        new_zoom,
-                        &mut commands,
-                        &mut meshes,
-                        &mut materials,
-                        &asset_server,
-                    );
+            // Tiles still wanted keep their fetch, dropping the other tasks cancels theirs
+            for (entity, pending_tile) in pending_tiles.iter() {
+                if let Some(index) = wanted_tiles.iter().position(|tile| tile == pending_tile) {
+                    wanted_tiles.remove(index);
+                } else {
+                    commands.entity(entity).despawn();
                 }
             }
+
+            for tile in wanted_tiles {
+                request_tile(tile, &mut commands, &thread_pool, &tile_sources);
+            }
         }
     }
 }
@@ -268,31 +435,60 @@ fn magic_eq(radius: f32, dist: f32) -> f32 {
 }
 
 fn request_tile(
-    x: u32,
-    y: u32,
-    z: u32,
+    tile: PendingTile,
     commands: &mut Commands,
     thread_pool: &Res<AsyncComputeTaskPool>,
+    tile_sources: &Res<TileSources>,
 ) {
+    let sources = (*tile_sources).clone();
+    let PendingTile { x, y, z } = tile;
     let task = thread_pool.spawn(async move {
-        async_compat::Compat::new(async {
-            match get_arcgis_image_tile(2_u32.pow(z) - x - 1, y, z).await {
-                Ok(_) => {}
-                Err(error) => {
-                    println!("Failed to download tile ({}, {}, {}): {:?}", x, y, z, error);
-                }
-            }
-            ()
+        let tile_x = 2_u32.pow(z) - x - 1;
+        let image = async_compat::Compat::new(async {
+            sources.get_tile(&sources.imagery, tile_x, y, z).await
         })
         .await;
+        // The tiles are only raised by heightmaps, quantized meshes are left
+        // to the terrain viewer
+        let topo = if sources.elevation.heightmap_format() == HeightmapFormat::QuantizedMesh {
+            Err(TileError::NotFound)
+        } else {
+            async_compat::Compat::new(async {
+                sources.get_tile(&sources.elevation, tile_x, y, z).await
+            })
+            .await
+        };
+        TileData {
+            x,
+            y,
+            z,
+            image,
+            topo,
+        }
     });
-    commands.spawn().insert(task);
+    commands.spawn().insert(task).insert(tile);
 }
 
-fn handle_tasks(mut commands: Commands, mut query_tasks: Query<(Entity, &mut Task<()>)>) {
+fn handle_tasks(
+    mut commands: Commands,
+    mut query_tasks: Query<(Entity, &mut Task<TileData>)>,
+    mut assets: TileAssets,
+    res: Res<UserPosition>,
+    tile_sources: Res<TileSources>,
+    globe_options: Res<GlobeOptions>,
+) {
     for (entity, mut task) in query_tasks.iter_mut() {
-        if let Some(_) = future::block_on(future::poll_once(&mut *task)) {
-            commands.entity(entity).remove::<Task<()>>().despawn();
+        if let Some(tile_data) = future::block_on(future::poll_once(&mut *task)) {
+            if tile_data.z == res.zoom {
+                generate_tile(
+                    tile_data,
+                    &mut commands,
+                    &mut assets,
+                    &tile_sources,
+                    &globe_options,
+                );
+            }
+            commands.entity(entity).remove::<Task<TileData>>().despawn();
         }
     }
 }
@@ -0,0 +1,537 @@
+use super::coord_utils::*;
+use super::map_services::{TileContents, TileError};
+use std::convert::TryInto;
+use std::io::{Cursor, Seek, SeekFrom, Write};
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::tags::Tag;
+
+/// How a source encodes the elevation in its tiles.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeightmapFormat {
+    /// Esri's LERC rasters, already on the tile's grid.
+    Lerc,
+    /// GeoTIFF rasters in degrees, e.g. SRTM from OpenTopography, which are
+    /// resampled to the tile's grid.
+    GeoTiff,
+    /// Mapbox Terrain-RGB PNGs: `-10000 + (R * 256² + G * 256 + B) / 10`.
+    TerrainRgb,
+    /// Mapzen Terrarium PNGs: `R * 256 + G + B / 256 - 32768`.
+    Terrarium,
+    /// Cesium quantized-mesh-1.0 tiles, which are already triangulated
+    /// instead of heightmaps. Their tileset has to use the web mercator
+    /// projection, with its rows counted from the south, i.e. `{-y}`.
+    QuantizedMesh,
+}
+
+impl HeightmapFormat {
+    /// The format of an archive's tiles, from the `encoding` metadata of
+    /// raster-dem tilesets when they have it. PNGs without it are assumed to
+    /// be Terrain-RGB, as in the Mapbox style spec.
+    pub fn for_tiles(file_extension: &str, encoding: Option<&str>) -> HeightmapFormat {
+        match (encoding, file_extension) {
+            (Some("terrarium"), _) => HeightmapFormat::Terrarium,
+            (Some("mapbox"), _) | (Some("terrain-rgb"), _) => HeightmapFormat::TerrainRgb,
+            (_, "tiff") | (_, "tif") => HeightmapFormat::GeoTiff,
+            (_, "terrain") => HeightmapFormat::QuantizedMesh,
+            (_, "png") | (_, "webp") => HeightmapFormat::TerrainRgb,
+            _ => HeightmapFormat::Lerc,
+        }
+    }
+}
+
+/// Elevations in meters on a grid covering a tile, in rows from north to
+/// south. The first and last rows and columns are on the tile's edges.
+/// Points without data are NaN.
+pub struct Heightmap {
+    pub n_cols: usize,
+    pub n_rows: usize,
+    pub data: Vec<f32>,
+}
+
+impl Heightmap {
+    /// Bilinear interpolation of the height at a point of the tile, from 0 to
+    /// 1 from its north west corner. Next to points without data, the nearest
+    /// point is used instead.
+    pub fn sample(&self, u: f32, v: f32) -> f32 {
+        let last_col = (self.n_cols - 1) as f32;
+        let last_row = (self.n_rows - 1) as f32;
+        let (col, row) = (
+            (u * last_col).clamp(0., last_col),
+            (v * last_row).clamp(0., last_row),
+        );
+        let height = |col: f32, row: f32| self.data[col as usize + row as usize * self.n_cols];
+        let (col_0, row_0) = (col.floor(), row.floor());
+        let (col_1, row_1) = ((col_0 + 1.).min(last_col), (row_0 + 1.).min(last_row));
+        let (col_t, row_t) = (col - col_0, row - row_0);
+        let north = height(col_0, row_0) * (1. - col_t) + height(col_1, row_0) * col_t;
+        let south = height(col_0, row_1) * (1. - col_t) + height(col_1, row_1) * col_t;
+        let interpolated = north * (1. - row_t) + south * row_t;
+        if interpolated.is_nan() {
+            height(col.round(), row.round())
+        } else {
+            interpolated
+        }
+    }
+}
+
+/// Samples per side of the grid GeoTIFF rasters are resampled to.
+const RESAMPLED_SIZE: usize = 257;
+
+// GeoTIFF tags, see http://docs.opengeospatial.org/is/19-008r4/19-008r4.html
+const MODEL_PIXEL_SCALE: Tag = Tag::Unknown(33550);
+const MODEL_TIEPOINT: Tag = Tag::Unknown(33922);
+const MODEL_TRANSFORMATION: Tag = Tag::Unknown(34264);
+const GEO_KEY_DIRECTORY: Tag = Tag::Unknown(34735);
+/// The nodata value as text, as written by GDAL.
+const GDAL_NODATA: Tag = Tag::Unknown(42113);
+const RASTER_TYPE_GEO_KEY: u16 = 1025;
+const RASTER_PIXEL_IS_POINT: u16 = 2;
+
+/// Decodes the heightmap of a tile's contents, which may be an ancestor's.
+pub fn decode_heightmap(
+    format: HeightmapFormat,
+    topo: &TileContents,
+) -> Result<Heightmap, TileError> {
+    let heightmap = match format {
+        HeightmapFormat::Lerc => {
+            let dataset = decode_lerc(&topo.data)?;
+            let n_cols = dataset.info.n_cols as usize;
+            let mut data: Vec<f32> = dataset.data.iter().map(|height| *height as f32).collect();
+            if let Some(valid_pixels) = lerc_valid_pixels(&topo.data) {
+                if valid_pixels.len() == data.len() {
+                    for (height, valid) in data.iter_mut().zip(valid_pixels) {
+                        if !valid {
+                            *height = f32::NAN;
+                        }
+                    }
+                }
+            }
+            Ok(Heightmap {
+                n_cols,
+                n_rows: data.len() / n_cols.max(1),
+                data,
+            })
+        }
+        HeightmapFormat::GeoTiff => {
+            let (x, y, z) = topo.tile;
+            decode_geotiff(&topo.data)?.resample(x, y, z)
+        }
+        HeightmapFormat::TerrainRgb => decode_rgb(&topo.data, |[r, g, b]| {
+            -10000. + (r as f32 * 65536. + g as f32 * 256. + b as f32) * 0.1
+        }),
+        HeightmapFormat::Terrarium => decode_rgb(&topo.data, |[r, g, b]| {
+            r as f32 * 256. + g as f32 + b as f32 / 256. - 32768.
+        }),
+        HeightmapFormat::QuantizedMesh => Err(TileError::Decode(
+            "quantized-mesh tiles aren't heightmaps".to_string(),
+        )),
+    }?;
+    if heightmap.n_cols < 2
+        || heightmap.n_rows < 2
+        || heightmap.data.len() != heightmap.n_cols * heightmap.n_rows
+    {
+        return Err(TileError::Decode(format!(
+            "heightmap of {} values can't be a grid of {}x{} points",
+            heightmap.data.len(),
+            heightmap.n_cols,
+            heightmap.n_rows
+        )));
+    }
+    Ok(heightmap)
+}
+
+/// Decodes an image with the elevation of each pixel encoded in its color.
+fn decode_rgb(data: &[u8], height: impl Fn([u8; 3]) -> f32) -> Result<Heightmap, TileError> {
+    let image = image::load_from_memory(data)
+        .map_err(|error| TileError::Decode(format!("invalid RGB heightmap: {}", error)))?
+        .to_rgb8();
+    let (width, rows) = (image.width() as usize, image.height() as usize);
+    let pixels: Vec<f32> = image.pixels().map(|pixel| height(pixel.0)).collect();
+    pixels_to_edges(width, rows, &pixels)
+}
+
+/// Turns a raster of pixels covering a tile into a grid with points on the
+/// tile's edges, one more per side, each the average of the pixels around it.
+fn pixels_to_edges(width: usize, height: usize, pixels: &[f32]) -> Result<Heightmap, TileError> {
+    if width == 0 || height == 0 {
+        return Err(TileError::Decode("empty RGB heightmap".to_string()));
+    }
+    let pixel = |col: usize, row: usize| pixels[col.min(width - 1) + row.min(height - 1) * width];
+    let mut data = Vec::with_capacity((width + 1) * (height + 1));
+    for row in 0..=height {
+        for col in 0..=width {
+            let (left, top) = (col.max(1) - 1, row.max(1) - 1);
+            data.push(
+                (pixel(left, top) + pixel(col, top) + pixel(left, row) + pixel(col, row)) / 4.,
+            );
+        }
+    }
+    Ok(Heightmap {
+        n_cols: width + 1,
+        n_rows: height + 1,
+        data,
+    })
+}
+
+fn decode_lerc(data: &[u8]) -> Result<lerc::LercDataset, TileError> {
+    // The decoder only reads from files
+    let mut file = tempfile::tempfile()?;
+    file.write_all(data)?;
+    file.seek(SeekFrom::Start(0))?;
+    lerc::decode_file(file).map_err(|_| TileError::Decode("invalid LERC heightmap".to_string()))
+}
+
+/// Reads which pixels of a LERC2 blob have data, the decoder leaves the others
+/// undefined. None when they all do, or for other versions of LERC.
+/// From: https://github.com/Esri/lerc/blob/master/src/LercLib/Lerc2.cpp
+fn lerc_valid_pixels(data: &[u8]) -> Option<Vec<bool>> {
+    if !data.starts_with(b"Lerc2 ") {
+        return None;
+    }
+    let int_at = |offset: usize| {
+        data.get(offset..offset + 4)
+            .map(|bytes| i32::from_le_bytes(bytes.try_into().unwrap()))
+    };
+    let version = int_at(6)?;
+    // After the version: a checksum from version 3, then the integers
+    // nRows, nCols, nDepth (from 4), numValidPixel, microBlockSize, blobSize,
+    // dataType and nBlobsMore (from 6), 4 flag bytes (from 6), the doubles
+    // maxZError, zMin, zMax, noData and noDataOrig (from 6)
+    let ints_offset = if version >= 3 { 14 } else { 10 };
+    let n_rows = int_at(ints_offset)?.max(0) as usize;
+    let n_cols = int_at(ints_offset + 4)?.max(0) as usize;
+    let num_valid = int_at(ints_offset + if version >= 4 { 12 } else { 8 })?.max(0) as usize;
+    let (n_ints, n_flags, n_doubles) = match version {
+        _ if version >= 6 => (8, 4, 5),
+        4 | 5 => (7, 0, 3),
+        _ => (6, 0, 3),
+    };
+    let mask_offset = ints_offset + n_ints * 4 + n_flags + n_doubles * 8;
+    let mask_length = int_at(mask_offset)?.max(0) as usize;
+
+    let n_pixels = n_rows * n_cols;
+    if num_valid == n_pixels {
+        return None;
+    }
+    if num_valid == 0 {
+        return Some(vec![false; n_pixels]);
+    }
+    // A run length encoded bit mask, the most significant bit first: counts of
+    // bytes to copy, or negated counts of times to repeat the next byte
+    let mut mask = Vec::with_capacity(n_pixels.div_ceil(8));
+    let mut rle = data.get(mask_offset + 4..mask_offset + 4 + mask_length)?;
+    loop {
+        let count = i16::from_le_bytes(rle.get(..2)?.try_into().unwrap());
+        rle = &rle[2..];
+        if count == i16::MIN {
+            break;
+        } else if count < 0 {
+            let byte = *rle.first()?;
+            mask.extend(std::iter::repeat_n(byte, -count as usize));
+            rle = &rle[1..];
+        } else {
+            mask.extend_from_slice(rle.get(..count as usize)?);
+            rle = &rle[count as usize..];
+        }
+    }
+    if mask.len() * 8 < n_pixels {
+        return None;
+    }
+    Some(
+        (0..n_pixels)
+            .map(|pixel| mask[pixel / 8] & (128 >> (pixel % 8)) != 0)
+            .collect(),
+    )
+}
+
+/// A north up raster in degrees, as read from a GeoTIFF.
+struct GeoRaster {
+    width: usize,
+    height: usize,
+    data: Vec<f32>,
+    nodata: Option<f32>,
+    /// Longitude and latitude of the center of the first pixel.
+    origin: (f64, f64),
+    /// Size of a pixel in degrees, positive going east and south.
+    pixel_size: (f64, f64),
+}
+
+fn geotiff_error(error: impl std::fmt::Display) -> TileError {
+    TileError::Decode(format!("invalid GeoTIFF heightmap: {}", error))
+}
+
+fn decode_geotiff(data: &[u8]) -> Result<GeoRaster, TileError> {
+    let mut decoder = Decoder::new(Cursor::new(data)).map_err(geotiff_error)?;
+    let (width, height) = decoder.dimensions().map_err(geotiff_error)?;
+    let f64_tag = |decoder: &mut Decoder<_>, tag| -> Result<Option<Vec<f64>>, TileError> {
+        match decoder.find_tag(tag).map_err(geotiff_error)? {
+            Some(value) => Ok(Some(value.into_f64_vec().map_err(geotiff_error)?)),
+            None => Ok(None),
+        }
+    };
+
+    // The corner of the first pixel, unless the raster is one of points
+    let (corner, pixel_size) = match (
+        f64_tag(&mut decoder, MODEL_PIXEL_SCALE)?,
+        f64_tag(&mut decoder, MODEL_TIEPOINT)?,
+        f64_tag(&mut decoder, MODEL_TRANSFORMATION)?,
+    ) {
+        (Some(scale), Some(tiepoint), _) if scale.len() >= 2 && tiepoint.len() >= 6 => (
+            (
+                tiepoint[3] - tiepoint[0] * scale[0],
+                tiepoint[4] + tiepoint[1] * scale[1],
+            ),
+            (scale[0], scale[1]),
+        ),
+        (_, _, Some(matrix)) if matrix.len() >= 8 && matrix[1] == 0. && matrix[4] == 0. => {
+            ((matrix[3], matrix[7]), (matrix[0], -matrix[5]))
+        }
+        _ => return Err(geotiff_error("no north up georeferencing")),
+    };
+    let pixel_is_point = match decoder.find_tag(GEO_KEY_DIRECTORY).map_err(geotiff_error)? {
+        Some(keys) => {
+            // A header of 4 shorts, then 4 per key: id, location, count, value
+            let keys: Vec<u16> = keys
+                .into_u32_vec()
+                .map_err(geotiff_error)?
+                .into_iter()
+                .map(|key| key as u16)
+                .collect();
+            keys.chunks_exact(4)
+                .skip(1)
+                .any(|key| key[0] == RASTER_TYPE_GEO_KEY && key[3] == RASTER_PIXEL_IS_POINT)
+        }
+        None => false,
+    };
+    let origin = if pixel_is_point {
+        corner
+    } else {
+        (corner.0 + pixel_size.0 / 2., corner.1 - pixel_size.1 / 2.)
+    };
+    let nodata = match decoder.find_tag(GDAL_NODATA).map_err(geotiff_error)? {
+        Some(value) => value.into_string().ok().and_then(|nodata| {
+            nodata
+                .trim_matches(|c: char| c == '\0' || c.is_whitespace())
+                .parse()
+                .ok()
+        }),
+        None => None,
+    };
+
+    let data = match decoder.read_image().map_err(geotiff_error)? {
+        DecodingResult::F32(data) => data,
+        DecodingResult::F64(data) => data.into_iter().map(|height| height as f32).collect(),
+        DecodingResult::I16(data) => data.into_iter().map(|height| height as f32).collect(),
+        DecodingResult::U16(data) => data.into_iter().map(|height| height as f32).collect(),
+        DecodingResult::I32(data) => data.into_iter().map(|height| height as f32).collect(),
+        _ => return Err(geotiff_error("unsupported sample format")),
+    };
+    let (width, height) = (width as usize, height as usize);
+    if data.len() != width * height {
+        return Err(geotiff_error("only single band rasters are supported"));
+    }
+    Ok(GeoRaster {
+        width,
+        height,
+        data,
+        nodata,
+        origin,
+        pixel_size,
+    })
+}
+
+impl GeoRaster {
+    fn get(&self, col: usize, row: usize) -> Option<f32> {
+        let height = self.data[col + row * self.width];
+        if Some(height) == self.nodata || height.is_nan() {
+            None
+        } else {
+            Some(height)
+        }
+    }
+
+    /// Bilinear interpolation between the pixels around a point, leaving out
+    /// the ones without data.
+    fn sample(&self, lon: f64, lat: f64) -> Option<f32> {
+        let col = ((lon - self.origin.0) / self.pixel_size.0).clamp(0., (self.width - 1) as f64);
+        let row = ((self.origin.1 - lat) / self.pixel_size.1).clamp(0., (self.height - 1) as f64);
+        let (col_0, row_0) = (col.floor() as usize, row.floor() as usize);
+        let (col_1, row_1) = (
+            (col_0 + 1).min(self.width - 1),
+            (row_0 + 1).min(self.height - 1),
+        );
+        let (col_t, row_t) = ((col - col_0 as f64) as f32, (row - row_0 as f64) as f32);
+        let neighbours = [
+            (col_0, row_0, (1. - col_t) * (1. - row_t)),
+            (col_1, row_0, col_t * (1. - row_t)),
+            (col_0, row_1, (1. - col_t) * row_t),
+            (col_1, row_1, col_t * row_t),
+        ];
+        let (sum, weights) = neighbours
+            .iter()
+            .filter_map(|&(col, row, weight)| Some((self.get(col, row)? * weight, weight)))
+            .fold((0., 0.), |(sum, weights), (height, weight)| {
+                (sum + height, weights + weight)
+            });
+        if weights > 0. {
+            Some(sum / weights)
+        } else {
+            None
+        }
+    }
+
+    /// Resamples the raster to a tile's grid.
+    fn resample(&self, x: u32, y: u32, z: u32) -> Result<Heightmap, TileError> {
+        if self.width == 0 || self.height == 0 {
+            return Err(geotiff_error("empty raster"));
+        }
+        let step = 1. / (RESAMPLED_SIZE - 1) as f64;
+        let mut data = Vec::with_capacity(RESAMPLED_SIZE * RESAMPLED_SIZE);
+        for row in 0..RESAMPLED_SIZE {
+            for col in 0..RESAMPLED_SIZE {
+                let (lat, lon) = tile_point_to_deg(
+                    x as f64 + col as f64 * step,
+                    y as f64 + row as f64 * step,
+                    z,
+                );
+                data.push(self.sample(lon, lat).unwrap_or(f32::NAN));
+            }
+        }
+        Ok(Heightmap {
+            n_cols: RESAMPLED_SIZE,
+            n_rows: RESAMPLED_SIZE,
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile_contents(data: Vec<u8>) -> TileContents {
+        TileContents {
+            data,
+            tile: (0, 0, 0),
+            levels_up: 0,
+            uv_offset: [0., 0.],
+            uv_scale: 1.,
+        }
+    }
+
+    /// A PNG of 2 by 2 pixels of one color.
+    fn png(color: [u8; 3]) -> Vec<u8> {
+        let image = image::RgbImage::from_pixel(2, 2, image::Rgb(color));
+        let mut data = Vec::new();
+        image::DynamicImage::ImageRgb8(image)
+            .write_to(&mut data, image::ImageOutputFormat::Png)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn rgb_heights() {
+        let terrain_rgb = tile_contents(png([1, 134, 160]));
+        let heightmap = decode_heightmap(HeightmapFormat::TerrainRgb, &terrain_rgb).unwrap();
+        assert_eq!((heightmap.n_cols, heightmap.n_rows), (3, 3));
+        assert!(heightmap.data.iter().all(|height| height.abs() < 0.01));
+
+        let terrarium = tile_contents(png([128, 100, 128]));
+        let heightmap = decode_heightmap(HeightmapFormat::Terrarium, &terrarium).unwrap();
+        assert!(heightmap.data.iter().all(|height| *height == 100.5));
+
+        let invalid = tile_contents(b"not a PNG".to_vec());
+        assert!(decode_heightmap(HeightmapFormat::TerrainRgb, &invalid).is_err());
+    }
+
+    #[test]
+    fn pixels_around_the_edges() {
+        let heightmap = pixels_to_edges(2, 2, &[0., 4., 8., 12.]).unwrap();
+        assert_eq!((heightmap.n_cols, heightmap.n_rows), (3, 3));
+        assert_eq!(heightmap.data, vec![0., 2., 4., 4., 6., 8., 8., 10., 12.]);
+        assert!(pixels_to_edges(0, 0, &[]).is_err());
+    }
+
+    /// A raster of the whole map in 1 degree pixels, each pixel's height its
+    /// longitude.
+    fn longitude_raster(nodata: Option<f32>) -> GeoRaster {
+        let (width, height) = (361, 171);
+        GeoRaster {
+            width,
+            height,
+            data: (0..width * height)
+                .map(|pixel| (pixel % width) as f32 - 180.)
+                .collect(),
+            nodata,
+            origin: (-180., 85.),
+            pixel_size: (1., 1.),
+        }
+    }
+
+    #[test]
+    fn geotiff_resampling() {
+        // The north east quarter of the map
+        let heightmap = longitude_raster(None).resample(1, 0, 1).unwrap();
+        assert_eq!((heightmap.n_cols, heightmap.n_rows), (257, 257));
+        let last_row = 256 * 257;
+        for row in &[0, last_row] {
+            assert!(heightmap.data[*row].abs() < 0.01);
+            assert!((heightmap.data[row + 128] - 90.).abs() < 0.01);
+            assert!((heightmap.data[row + 256] - 180.).abs() < 0.01);
+        }
+
+        // Everything west of the meridian without data
+        let mut raster = longitude_raster(Some(-1.));
+        for height in raster.data.iter_mut().filter(|height| **height < 0.) {
+            *height = -1.;
+        }
+        let heightmap = raster.resample(0, 1, 1).unwrap();
+        assert!(heightmap.data[..255].iter().all(|height| height.is_nan()));
+        // Within a pixel of the data, only the pixels with data are used
+        assert_eq!(heightmap.data[255], 0.);
+        assert_eq!(heightmap.data[256], 0.);
+    }
+
+    /// A LERC2 version 2 header followed by a run length encoded mask.
+    fn lerc2_blob(n_rows: i32, n_cols: i32, num_valid: i32, rle: &[u8]) -> Vec<u8> {
+        let mut blob = b"Lerc2 ".to_vec();
+        // The version, nRows, nCols, numValidPixel, microBlockSize, blobSize
+        // and dataType
+        for int in [2, n_rows, n_cols, num_valid, 8, 0, 1].iter() {
+            blob.extend_from_slice(&int.to_le_bytes());
+        }
+        // maxZError, zMin and zMax
+        blob.extend_from_slice(&[0; 24]);
+        blob.extend_from_slice(&(rle.len() as i32).to_le_bytes());
+        blob.extend_from_slice(rle);
+        blob
+    }
+
+    #[test]
+    fn lerc_masks() {
+        let end = i16::MIN.to_le_bytes();
+        // Two bytes copied
+        let rle = [&2_i16.to_le_bytes()[..], &[0b1010_1010, 0b1000_0000], &end].concat();
+        let valid = lerc_valid_pixels(&lerc2_blob(3, 3, 5, &rle)).unwrap();
+        assert_eq!(
+            valid,
+            vec![true, false, true, false, true, false, true, false, true]
+        );
+        // A byte repeated twice
+        let rle = [&(-2_i16).to_le_bytes()[..], &[0b1111_0000], &end].concat();
+        let valid = lerc_valid_pixels(&lerc2_blob(3, 3, 6, &rle)).unwrap();
+        assert_eq!(
+            valid,
+            vec![true, true, true, true, false, false, false, false, true]
+        );
+
+        assert_eq!(lerc_valid_pixels(&lerc2_blob(3, 3, 9, &end)), None);
+        assert_eq!(
+            lerc_valid_pixels(&lerc2_blob(3, 3, 0, &end)),
+            Some(vec![false; 9])
+        );
+        // Truncated
+        assert_eq!(lerc_valid_pixels(&lerc2_blob(3, 3, 5, &rle[..2])), None);
+        assert_eq!(lerc_valid_pixels(b"CntZImage "), None);
+    }
+}
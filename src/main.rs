@@ -5,12 +5,15 @@ use bevy::{
 };
 use bevy_egui::{egui, EguiContext, EguiPlugin};
 use futures_lite::future;
+use std::collections::HashSet;
 
 mod terrain_mesh;
 use terrain_mesh::*;
 
 mod coord_utils;
-use coord_utils::*;
+
+mod downloader;
+mod heightmap;
 
 mod camera_utils;
 use camera_utils::*;
@@ -18,13 +21,34 @@ use camera_utils::*;
 mod map_services;
 use map_services::*;
 
+#[cfg(test)]
+mod mock_tile_server;
+
+mod mbtiles;
+mod pmtiles;
+mod quantized_mesh;
+mod rtin;
+mod terrain_lod;
+use terrain_lod::*;
+
+mod tile_cache;
+mod tile_requests;
+
 #[derive(Debug, Clone, Copy)]
 struct UserPosition {
     lat: f64,
     lon: f64,
+    /// The terrain is shown at most at this zoom level.
     zoom: u32,
 }
 
+/// A tile being loaded, on the entity of its task.
+struct PendingTile {
+    base_lat: f64,
+    base_lon: f64,
+    tile: TileId,
+}
+
 fn main() {
     App::build()
         .insert_resource(Msaa { samples: 4 })
@@ -34,11 +58,19 @@ fn main() {
         })
         .add_plugins(DefaultPlugins)
         .insert_resource(ClearColor(Color::rgb(0., 0., 0.)))
+        .insert_resource(TileSources::from_args())
         .insert_resource(UiState {
-            detail_level: 10,
+            detail_level: 13,
+            max_screen_error: 2.,
+            resample: false,
+            mesh_resolution: 65,
+            mesh_max_error: 1.,
+            nodata: NoData::Interpolate,
             lat: "38.272688".to_string(),
             lon: "-120.234375".to_string(),
+            cache_usage: Vec::new(),
         })
+        .insert_resource(TerrainLod::default())
         .add_event::<MouseEvents>()
         .add_plugin(EguiPlugin)
         .add_system(controls.system())
@@ -47,39 +79,56 @@ fn main() {
         .add_system(camera_motion_system.system())
         .add_system(handle_tasks.system())
         .add_system(on_camera_updated.system())
-        .add_system(on_user_position_updated.system())
+        .add_system(select_terrain_tiles.system())
+        .add_system(request_terrain_tiles.system())
+        .add_system(retire_terrain_tiles.system())
         .run();
 }
 
 fn handle_tasks(
     mut commands: Commands,
-    mut query_tasks: Query<(Entity, &mut Task<TileInfo>)>,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-    asset_server: Res<AssetServer>,
-    current_tiles: Query<(Entity, &TileInfo)>,
-    user_position_query: Query<&UserPosition>,
+    mut query_tasks: Query<(Entity, &mut Task<(TileInfo, TileData)>)>,
+    mut assets: TerrainAssets,
+    tile_sources: Res<TileSources>,
+    lod: Res<TerrainLod>,
+    ui_state: Res<UiState>,
 ) {
-    let user_pos = user_position_query.single().unwrap();
-    for (tile_entity, tile) in current_tiles.iter() {
-        if tile.z != user_pos.zoom || tile.base_lat != user_pos.lat || tile.base_lon != user_pos.lon {
-            commands.entity(tile_entity).despawn();
-        }
-    }
-
     for (entity, mut task) in query_tasks.iter_mut() {
-        if let Some(tile_info) = future::block_on(future::poll_once(&mut *task)) {
-            if tile_info.z == user_pos.zoom {
+        if let Some((tile_info, tile_data)) = future::block_on(future::poll_once(&mut *task)) {
+            let tile = (tile_info.x, tile_info.y, tile_info.z);
+            // The neighbours' levels are those when the tile is loaded, the
+            // skirts hide the cracks if they change afterwards
+            if lod.selected.contains(&tile) {
+                let origin = SceneOrigin::new(tile_info.base_lat, tile_info.base_lon);
+                let (_, tile_size) = origin.tile_rect(tile.0, tile.1, tile.2);
+                let mesh_options = TerrainMeshOptions {
+                    resolution: if ui_state.resample {
+                        Some(ui_state.mesh_resolution)
+                    } else {
+                        None
+                    },
+                    max_error: Some(ui_state.mesh_max_error),
+                    tile_size,
+                    height_scale: HEIGHT_SCALE,
+                    nodata: ui_state.nodata,
+                    skirt_depth: Some(tile_size * 0.05),
+                    edge_levels: terrain_lod::edge_levels(&lod.selected, tile),
+                };
                 setup_terrain(
                     &mut commands,
-                    &mut meshes,
-                    &mut materials,
-                    &asset_server,
+                    &mut assets,
+                    tile_sources.imagery.file_extension(),
+                    tile_sources.elevation.heightmap_format(),
                     tile_info,
+                    tile_data,
+                    &mesh_options,
                 );
             }
 
-            commands.entity(entity).remove::<Task<TileInfo>>().despawn();
+            commands
+                .entity(entity)
+                .remove::<Task<(TileInfo, TileData)>>()
+                .despawn();
         }
     }
 }
@@ -116,7 +165,7 @@ fn setup(mut commands: Commands) {
     commands.spawn().insert(UserPosition {
         lat: 38.272688,
         lon: -120.234375,
-        zoom: 10,
+        zoom: 13,
     });
 }
 
@@ -138,66 +187,217 @@ fn on_camera_updated(ui_state: Res<UiState>, mut user_position_query: Query<&mut
     }
 }
 
-fn on_user_position_updated(
-    query: Query<&UserPosition, Changed<UserPosition>>,
+fn select_terrain_tiles(
+    camera_query: Query<(&Transform, &PerspectiveProjection), With<OrbitCamera>>,
+    user_position_query: Query<&UserPosition>,
+    windows: Res<Windows>,
+    ui_state: Res<UiState>,
+    mut lod: ResMut<TerrainLod>,
+) {
+    let (camera_transform, projection) = match camera_query.single() {
+        Ok(camera) => camera,
+        Err(_) => return,
+    };
+    let user_pos = match user_position_query.single() {
+        Ok(user_pos) => user_pos,
+        Err(_) => return,
+    };
+    let viewport_height = match windows.get_primary() {
+        Some(window) => window.height(),
+        None => return,
+    };
+
+    let selected = select_tiles(
+        &SceneOrigin::new(user_pos.lat, user_pos.lon),
+        &LodCamera {
+            position: camera_transform.translation,
+            fov: projection.fov,
+            viewport_height,
+        },
+        &LodOptions {
+            max_zoom: user_pos.zoom,
+            max_screen_error: ui_state.max_screen_error,
+        },
+    );
+    if selected != lod.selected {
+        lod.selected = selected;
+    }
+}
+
+/// Starts loading the selected tiles that aren't shown or being loaded, and
+/// cancels the loading of those no longer selected.
+fn request_terrain_tiles(
     mut commands: Commands,
+    lod: Res<TerrainLod>,
+    user_position_query: Query<&UserPosition>,
+    pending_tiles: Query<(Entity, &PendingTile)>,
+    current_tiles: Query<&TileInfo>,
     thread_pool: Res<AsyncComputeTaskPool>,
+    tile_sources: Res<TileSources>,
 ) {
-    if let Ok(user_pos) = query.single() {
-        println!("{:?}", user_pos);
+    let user_pos = match user_position_query.single() {
+        Ok(user_pos) => user_pos,
+        Err(_) => return,
+    };
 
-        let min_zoom = 9;
-        let (top_x, top_y) = deg2num(user_pos.lat, user_pos.lon, user_pos.zoom);
+    let mut requested = HashSet::new();
+    for (entity, pending) in pending_tiles.iter() {
+        if pending.base_lat == user_pos.lat
+            && pending.base_lon == user_pos.lon
+            && lod.selected.contains(&pending.tile)
+        {
+            requested.insert(pending.tile);
+        } else {
+            // Dropping the task cancels the downloads nobody else is waiting for
+            commands.entity(entity).despawn();
+        }
+    }
+    for tile in current_tiles.iter() {
+        if tile.base_lat == user_pos.lat && tile.base_lon == user_pos.lon {
+            requested.insert((tile.x, tile.y, tile.z));
+        }
+    }
+
+    for &(x, y, z) in lod.selected.difference(&requested) {
         let lat = user_pos.lat;
         let lon = user_pos.lon;
-        let z = user_pos.zoom;
-
-        if user_pos.zoom > min_zoom {
-            let n = user_pos.zoom - min_zoom;
-            for x_i in 0..(n * n) {
-                for y_i in 0..(n * n) {
-                    let x = top_x + x_i;
-                    let y = top_y + y_i;
-
-                    let task = thread_pool.spawn(async move {
-                        let topo_filename = async_compat::Compat::new(async {
-                            get_arcgis_topo_tile(x, y, z).await
-                        })
-                        .await
-                        .unwrap_or("".to_string());
-
-                        let image_filename = async_compat::Compat::new(async {
-                            get_arcgis_image_tile(x, y, z).await
-                        })
-                        .await
-                        .unwrap_or("".to_string());
-
-                        TileInfo {
-                            base_lat: lat,
-                            base_lon: lon,
-                            x_offset: x_i,
-                            y_offset: y_i,
-                            z,
-                            topo_filename,
-                            image_filename,
-                        }
-                    });
-                    commands.spawn().insert(task);
+        let sources = tile_sources.clone();
+
+        let task = thread_pool.spawn(async move {
+            let (topo, image) = async_compat::Compat::new(future::zip(
+                sources.get_tile(&sources.elevation, x, y, z),
+                sources.get_tile(&sources.imagery, x, y, z),
+            ))
+            .await;
+
+            // Only needed for the normals along the edges, so they aren't
+            // downloaded: most are cached once loaded for their own tiles,
+            // and the slopes are taken from the edges themselves otherwise
+            let last_tile = 2_u32.pow(z) - 1;
+            let neighbours = [
+                (x.checked_sub(1), Some(y)),
+                (Some(x), y.checked_sub(1)),
+                (Some(x + 1).filter(|x| *x <= last_tile), Some(y)),
+                (Some(x), Some(y + 1).filter(|y| *y <= last_tile)),
+            ];
+            let mut topo_neighbours = [None, None, None, None];
+            for (contents, neighbour) in topo_neighbours.iter_mut().zip(&neighbours) {
+                if let (Some(x), Some(y)) = *neighbour {
+                    *contents = sources.get_cached_tile(&sources.elevation, x, y, z);
                 }
             }
+
+            (
+                TileInfo {
+                    base_lat: lat,
+                    base_lon: lon,
+                    x,
+                    y,
+                    z,
+                    topo_error: None,
+                    image_error: None,
+                },
+                TileData {
+                    topo,
+                    image,
+                    topo_neighbours,
+                },
+            )
+        });
+        commands.spawn().insert(task).insert(PendingTile {
+            base_lat: lat,
+            base_lon: lon,
+            tile: (x, y, z),
+        });
+    }
+}
+
+/// Removes the tiles no longer selected once the tiles replacing them are
+/// loaded. Until then, those are hidden so that they don't overlap.
+fn retire_terrain_tiles(
+    mut commands: Commands,
+    lod: Res<TerrainLod>,
+    user_position_query: Query<&UserPosition>,
+    mut current_tiles: Query<(Entity, &TileInfo, &mut Visible)>,
+) {
+    let user_pos = match user_position_query.single() {
+        Ok(user_pos) => user_pos,
+        Err(_) => return,
+    };
+
+    let mut loaded = HashSet::new();
+    for (tile_entity, tile, _) in current_tiles.iter() {
+        if tile.base_lat != user_pos.lat || tile.base_lon != user_pos.lon {
+            commands.entity(tile_entity).despawn();
+        } else {
+            loaded.insert((tile.x, tile.y, tile.z));
+        }
+    }
+
+    let mut retained = Vec::new();
+    for (tile_entity, tile, _) in current_tiles.iter() {
+        let tile = (tile.x, tile.y, tile.z);
+        if !loaded.contains(&tile) || lod.selected.contains(&tile) {
+            continue;
+        }
+        let replaced = covering_tiles(&lod.selected, tile)
+            .iter()
+            .all(|covering| loaded.contains(covering));
+        if replaced {
+            commands.entity(tile_entity).despawn();
+        } else {
+            retained.push(tile);
+        }
+    }
+
+    for (_, tile, mut visible) in current_tiles.iter_mut() {
+        let tile = (tile.x, tile.y, tile.z);
+        let is_visible = !lod.selected.contains(&tile)
+            || !retained.iter().any(|retained| tiles_overlap(*retained, tile));
+        if visible.is_visible != is_visible {
+            visible.is_visible = is_visible;
         }
     }
 }
 
 struct UiState {
     detail_level: u32,
+    max_screen_error: f32,
+    /// Whether the heightmaps are resampled to `mesh_resolution` points a
+    /// side, for the meshes of the tiles loaded from then on.
+    resample: bool,
+    mesh_resolution: u32,
+    /// In meters, for the meshes of the tiles loaded from then on.
+    mesh_max_error: f32,
+    /// For the meshes of the tiles loaded from then on.
+    nodata: NoData,
     lat: String,
     lon: String,
+    cache_usage: Vec<tile_cache::CacheUsage>,
 }
 
-fn controls(egui_context: ResMut<EguiContext>, mut ui_state: ResMut<UiState>) {
+fn controls(
+    egui_context: ResMut<EguiContext>,
+    mut ui_state: ResMut<UiState>,
+    tile_sources: Res<TileSources>,
+    tiles: Query<&TileInfo>,
+) {
     egui::Window::new("Settings").show(egui_context.ctx(), |ui| {
-        ui.add(egui::Slider::new(&mut ui_state.detail_level, 10..=13).text("Detail"));
+        ui.add(egui::Slider::new(&mut ui_state.detail_level, ROOT_ZOOM..=15).text("Max detail"));
+        ui.add(
+            egui::Slider::new(&mut ui_state.max_screen_error, 1.0..=16.0).text("Max error (px)"),
+        );
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut ui_state.resample, "Resample");
+            ui.add(egui::Slider::new(&mut ui_state.mesh_resolution, 2..=257).text("Mesh points"));
+        });
+        ui.add(egui::Slider::new(&mut ui_state.mesh_max_error, 0.0..=50.0).text("Mesh error (m)"));
+        ui.horizontal(|ui| {
+            ui.label("No data: ");
+            ui.radio_value(&mut ui_state.nodata, NoData::Interpolate, "Interpolate");
+            ui.radio_value(&mut ui_state.nodata, NoData::Height(0.), "Sea level");
+            ui.radio_value(&mut ui_state.nodata, NoData::Drop, "Drop");
+        });
         ui.horizontal(|ui| {
             ui.label("Latitude: ");
             ui.text_edit_singleline(&mut ui_state.lat);
@@ -207,5 +407,41 @@ fn controls(egui_context: ResMut<EguiContext>, mut ui_state: ResMut<UiState>) {
             ui.label("Longitude: ");
             ui.text_edit_singleline(&mut ui_state.lon);
         });
+
+        ui.collapsing("Cache", |ui| {
+            if ui.button("Refresh usage").clicked() {
+                ui_state.cache_usage = tile_sources.cache.usage().unwrap_or_else(|error| {
+                    println!("Failed to read the cache usage: {}", error);
+                    Vec::new()
+                });
+            }
+            for usage in ui_state.cache_usage.iter() {
+                ui.label(format!(
+                    "{} z{}: {} tiles, {:.1} MB",
+                    usage.layer,
+                    usage.zoom,
+                    usage.tiles,
+                    usage.bytes as f64 / (1024. * 1024.)
+                ));
+            }
+        });
+
+        // The tiles shown flat or untextured, and why
+        ui.collapsing("Missing data", |ui| {
+            for tile in tiles.iter() {
+                let errors = [
+                    ("elevation", &tile.topo_error),
+                    ("imagery", &tile.image_error),
+                ];
+                for (layer, error) in errors.iter() {
+                    if let Some(error) = error {
+                        ui.label(format!(
+                            "{}/{}/{} {}: {}",
+                            tile.z, tile.x, tile.y, layer, error
+                        ));
+                    }
+                }
+            }
+        });
     });
 }
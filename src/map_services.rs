@@ -1,59 +1,747 @@
 use super::coord_utils::*;
-use bytes::Buf;
-use std::fs::File;
+use super::downloader::*;
+use super::heightmap::HeightmapFormat;
+use super::mbtiles::*;
+use super::pmtiles::*;
+use super::tile_cache::*;
+use super::tile_requests::*;
+use reqwest::StatusCode;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
 
-async fn download_to_file(url: &str, filename: &str) -> Result<(), Box<dyn std::error::Error>> {
-    match File::open(filename) {
-        Ok(_) => {
-            // println!("File exists");
-            Ok(())
+/// Why a tile couldn't be loaded. Cloneable so that a failed fetch can be
+/// reported to everyone waiting for the tile.
+#[derive(Debug, Clone)]
+pub enum TileError {
+    /// The request failed before getting a complete response.
+    Network(Arc<reqwest::Error>),
+    /// The server answered with an error status.
+    HttpStatus(StatusCode),
+    /// The source has no such tile, e.g. elevation over the ocean or a zoom
+    /// level it doesn't serve.
+    NotFound,
+    /// The tile's contents aren't in the expected format.
+    Decode(String),
+    /// Reading or writing the cache failed.
+    Io(Arc<std::io::Error>),
+    /// Nobody needs the tile any more.
+    Cancelled,
+    /// The tile isn't cached and we may not download it.
+    Offline,
+}
+
+impl fmt::Display for TileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TileError::Network(error) => write!(f, "network error: {}", error),
+            TileError::HttpStatus(status) => write!(f, "server answered {}", status),
+            TileError::NotFound => write!(f, "tile not found"),
+            TileError::Decode(message) => write!(f, "invalid tile: {}", message),
+            TileError::Io(error) => write!(f, "I/O error: {}", error),
+            TileError::Cancelled => write!(f, "cancelled"),
+            TileError::Offline => write!(f, "not available offline"),
         }
-        Err(_) => {
-            let client = reqwest::Client::new();
-            let resp = client
-                .get(url)
-                .send()
-                .await?
-                .bytes()
-                .await?;
-            let mut file = File::create(filename)?;
-            ::std::io::copy(&mut resp.reader(), &mut file)?; //TODO: Delete file if failed?
-            Ok(())
+    }
+}
+
+impl std::error::Error for TileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TileError::Network(error) => Some(&**error),
+            TileError::Io(error) => Some(&**error),
+            _ => None,
         }
     }
 }
 
-pub async fn _get_opentopography_tile(
-    x: u32,
-    y: u32,
-    z: u32,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let (north, west) = num2deg(x, y, z);
-    let (south, east) = num2deg(x + 1, y + 1, z);
-    let url = format!("https://portal.opentopography.org/API/globaldem?demtype=SRTMGL1&west={}&east={}&south={}&north={}&outputFormat=GTiff", west, east, south, north);
-    let filename = format!("assets/images/topo_{}_{}_{}.tiff", x, y, z);
-    download_to_file(&url, &filename).await?;
-    Ok(filename)
+impl From<reqwest::Error> for TileError {
+    fn from(error: reqwest::Error) -> TileError {
+        TileError::Network(Arc::new(error))
+    }
+}
+
+impl From<std::io::Error> for TileError {
+    fn from(error: std::io::Error) -> TileError {
+        TileError::Io(Arc::new(error))
+    }
+}
+
+impl From<rusqlite::Error> for TileError {
+    fn from(error: rusqlite::Error) -> TileError {
+        std::io::Error::new(std::io::ErrorKind::Other, error).into()
+    }
 }
 
-pub async fn get_arcgis_topo_tile(
+/// How the rows of a tile pyramid are numbered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TileScheme {
+    /// Slippy map tiles, row 0 is the northernmost row.
+    Xyz,
+    /// TMS tiles, row 0 is the southernmost row.
+    Tms,
+}
+
+impl TileScheme {
+    /// Converts a slippy map row into the row used by this scheme.
+    pub fn row(&self, y: u32, z: u32) -> u32 {
+        match self {
+            TileScheme::Xyz => y,
+            TileScheme::Tms => 2_u32.pow(z) - y - 1,
+        }
+    }
+}
+
+/// A tile server or a local tile archive. Tiles are always requested with
+/// slippy map coordinates, each source is responsible for translating them
+/// to its own addressing.
+pub trait TileSource: Send + Sync {
+    /// Identifies the source's tiles in caches, must be unique among the
+    /// sources in use.
+    fn name(&self) -> &str;
+    /// Tile URL, see `fill_url_template` for the supported placeholders.
+    /// Empty for sources that aren't downloaded.
+    fn url_template(&self) -> &str {
+        ""
+    }
+    fn tile_scheme(&self) -> TileScheme {
+        TileScheme::Xyz
+    }
+    /// Values for the `{s}` placeholder.
+    fn subdomains(&self) -> &[String] {
+        &[]
+    }
+    fn file_extension(&self) -> &str;
+    fn max_zoom(&self) -> u32;
+    fn attribution(&self) -> &str;
+    /// How the tiles encode elevation, for elevation sources.
+    fn heightmap_format(&self) -> HeightmapFormat {
+        HeightmapFormat::Lerc
+    }
+
+    fn tile_url(&self, x: u32, y: u32, z: u32) -> Option<String> {
+        if self.url_template().is_empty() {
+            return None;
+        }
+        Some(fill_url_template(
+            self.url_template(),
+            x,
+            y,
+            z,
+            self.tile_scheme(),
+            self.subdomains(),
+        ))
+    }
+
+    /// Reads a tile without going to the network, for local archives.
+    fn read_local(&self, _x: u32, _y: u32, _z: u32) -> Result<Option<Vec<u8>>, TileError> {
+        Ok(None)
+    }
+}
+
+/// Replaces the placeholders of a tile URL template:
+/// - `{x}`, `{y}` and `{z}`: the tile column, row and zoom, the row being
+///   numbered according to `scheme`
+/// - `{-y}`: the row counted from the bottom of the map, as in TMS
+/// - `{quadkey}`: the Bing maps quadkey of the tile
+/// - `{s}`: one of the subdomains, picked from the tile position so that
+///   neighbouring tiles are spread across all of them. Without subdomains,
+///   it's left out, along with the dot after it when it starts the host name,
+///   as most servers answer on their bare domain too
+pub fn fill_url_template(
+    template: &str,
     x: u32,
     y: u32,
     z: u32,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let url = format!("https://services.arcgisonline.com/arcgis/rest/services/WorldElevation3D/Terrain3D/ImageServer/tile/{}/{}/{}", z, y, x);
-    let filename = format!("assets/images/topo_{}_{}_{}.lerc", x, y, z);
-    download_to_file(&url, &filename).await?;
-    Ok(filename)
+    scheme: TileScheme,
+    subdomains: &[String],
+) -> String {
+    let mut url = template
+        .replace("{z}", &z.to_string())
+        .replace("{x}", &x.to_string())
+        .replace("{y}", &scheme.row(y, z).to_string())
+        .replace("{-y}", &TileScheme::Tms.row(y, z).to_string());
+    let mut subdomain_index = x as usize + y as usize;
+    if url.contains("{quadkey}") {
+        let quadkey = tile_to_quadkey(x, y, z);
+        // Quadkey servers shard on the last digit, which also spreads neighbouring tiles
+        subdomain_index = quadkey
+            .chars()
+            .last()
+            .and_then(|c| c.to_digit(4))
+            .unwrap_or(0) as usize;
+        url = url.replace("{quadkey}", &quadkey);
+    }
+    if subdomains.is_empty() {
+        url = url.replace("//{s}.", "//").replace("{s}", "");
+    } else {
+        let subdomain = &subdomains[subdomain_index % subdomains.len()];
+        url = url.replace("{s}", subdomain);
+    }
+    url
+}
+
+/// A source configured entirely from a URL template, e.g.
+/// `https://{s}.tile.example.com/{z}/{x}/{y}.png`.
+pub struct UrlTemplateSource {
+    pub name: String,
+    pub url_template: String,
+    pub tile_scheme: TileScheme,
+    pub subdomains: Vec<String>,
+    pub file_extension: String,
+    pub max_zoom: u32,
+    pub attribution: String,
+    pub heightmap_format: HeightmapFormat,
+}
+
+impl Default for UrlTemplateSource {
+    fn default() -> UrlTemplateSource {
+        UrlTemplateSource {
+            name: String::new(),
+            url_template: String::new(),
+            tile_scheme: TileScheme::Xyz,
+            subdomains: Vec::new(),
+            file_extension: "png".to_string(),
+            max_zoom: 19,
+            attribution: String::new(),
+            heightmap_format: HeightmapFormat::TerrainRgb,
+        }
+    }
+}
+
+impl TileSource for UrlTemplateSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn url_template(&self) -> &str {
+        &self.url_template
+    }
+
+    fn tile_scheme(&self) -> TileScheme {
+        self.tile_scheme
+    }
+
+    fn subdomains(&self) -> &[String] {
+        &self.subdomains
+    }
+
+    fn file_extension(&self) -> &str {
+        &self.file_extension
+    }
+
+    fn max_zoom(&self) -> u32 {
+        self.max_zoom
+    }
+
+    fn attribution(&self) -> &str {
+        &self.attribution
+    }
+
+    fn heightmap_format(&self) -> HeightmapFormat {
+        self.heightmap_format
+    }
 }
 
-pub async fn get_arcgis_image_tile(
+/// Mapzen's Terrarium elevation tiles, hosted on AWS.
+pub fn terrarium_elevation() -> UrlTemplateSource {
+    UrlTemplateSource {
+        name: "terrarium".to_string(),
+        url_template: "https://s3.amazonaws.com/elevation-tiles-prod/terrarium/{z}/{x}/{y}.png"
+            .to_string(),
+        max_zoom: 15,
+        attribution: "Mapzen, terrain tiles from AWS Open Data".to_string(),
+        heightmap_format: HeightmapFormat::Terrarium,
+        ..Default::default()
+    }
+}
+
+/// An elevation source for a URL template, going by its file extension.
+pub fn elevation_url_source(url_template: &str) -> UrlTemplateSource {
+    let path = url_template.split('?').next().unwrap_or_default();
+    let file_name = path.rsplit('/').next().unwrap_or_default();
+    let file_extension = match file_name.rsplit_once('.') {
+        Some((_, extension)) => extension,
+        None => "png",
+    };
+    let heightmap_format = HeightmapFormat::for_tiles(file_extension, None);
+    UrlTemplateSource {
+        name: "elevation".to_string(),
+        url_template: url_template.to_string(),
+        // Quantized-mesh tiles are numbered as in TMS
+        tile_scheme: match heightmap_format {
+            HeightmapFormat::QuantizedMesh => TileScheme::Tms,
+            _ => TileScheme::Xyz,
+        },
+        file_extension: file_extension.to_string(),
+        heightmap_format,
+        ..Default::default()
+    }
+}
+
+/// Returns the contents of a tile, downloading it unless it's already cached.
+/// Stale cached tiles are revalidated with the server, and still used if it
+/// can't be reached or when offline.
+pub async fn get_tile(
+    source: &dyn TileSource,
+    cache: &dyn TileCache,
+    downloader: &Downloader,
     x: u32,
     y: u32,
     z: u32,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let url = format!("https://server.arcgisonline.com/ArcGIS/rest/services/World_Imagery/MapServer/tile/{}/{}/{}", z, y, x);
-    let filename = format!("assets/images/imagery_{}_{}_{}.jpeg", x, y, z);
-    download_to_file(&url, &filename).await?;
-    Ok(filename)
+) -> Result<Vec<u8>, TileError> {
+    if z > source.max_zoom() {
+        return Err(TileError::NotFound);
+    }
+    if let Some(data) = source.read_local(x, y, z)? {
+        return Ok(data);
+    }
+    let mut cached = cache.get(source, x, y, z)?;
+    if let Some(tile) = &cached {
+        if !is_valid_tile(source.file_extension(), &tile.data) {
+            println!(
+                "Cached tile ({}, {}, {}) is corrupt, downloading it again",
+                x, y, z
+            );
+            cached = None;
+        } else if downloader.is_offline() || tile.metadata.is_fresh(unix_time(SystemTime::now())) {
+            return Ok(cached.unwrap().data);
+        }
+    }
+    if downloader.is_offline() {
+        return Err(TileError::Offline);
+    }
+    let url = source.tile_url(x, y, z).ok_or(TileError::NotFound)?;
+    let download = downloader
+        .download(&url, cached.as_ref().map(|tile| &tile.metadata))
+        .await;
+    match (download, cached) {
+        (Ok(Download::Modified(data, metadata)), _) => {
+            if !is_valid_tile(source.file_extension(), &data) {
+                return Err(TileError::Decode(format!(
+                    "not a {} file",
+                    source.file_extension()
+                )));
+            }
+            cache.put(source, x, y, z, &data, &metadata)?;
+            Ok(data)
+        }
+        (Ok(Download::NotModified(metadata)), Some(tile)) => {
+            cache.put_metadata(source, x, y, z, &metadata)?;
+            Ok(tile.data)
+        }
+        (Ok(Download::NotModified(_)), None) => Err(TileError::Decode(
+            "not modified response for an uncached tile".to_string(),
+        )),
+        (Err(TileError::NotFound), _) => Err(TileError::NotFound),
+        (Err(error), Some(tile)) => {
+            println!(
+                "Failed to revalidate tile ({}, {}, {}), using the cached one: {}",
+                x, y, z, error
+            );
+            Ok(tile.data)
+        }
+        (Err(error), None) => Err(error),
+    }
+}
+
+pub struct ArcGisImagery;
+
+impl TileSource for ArcGisImagery {
+    fn name(&self) -> &str {
+        "imagery"
+    }
+
+    fn url_template(&self) -> &str {
+        "https://server.arcgisonline.com/ArcGIS/rest/services/World_Imagery/MapServer/tile/{z}/{y}/{x}"
+    }
+
+    fn file_extension(&self) -> &str {
+        "jpeg"
+    }
+
+    fn max_zoom(&self) -> u32 {
+        19
+    }
+
+    fn attribution(&self) -> &str {
+        "Esri, Maxar, Earthstar Geographics, and the GIS User Community"
+    }
+}
+
+pub struct ArcGisElevation;
+
+impl TileSource for ArcGisElevation {
+    fn name(&self) -> &str {
+        "topo"
+    }
+
+    fn url_template(&self) -> &str {
+        "https://services.arcgisonline.com/arcgis/rest/services/WorldElevation3D/Terrain3D/ImageServer/tile/{z}/{y}/{x}"
+    }
+
+    fn file_extension(&self) -> &str {
+        "lerc"
+    }
+
+    fn max_zoom(&self) -> u32 {
+        16
+    }
+
+    fn attribution(&self) -> &str {
+        "Esri, USGS, NGA, NASA, CGIAR, GEBCO, N Robinson, NCEAS, NLS, OS, NMA, Geodatastyrelsen and the GIS User Community"
+    }
+}
+
+/// SRTM GeoTIFFs from OpenTopography, requested by bounding box. The API key
+/// is read from `OPENTOPOGRAPHY_API_KEY`.
+pub struct OpenTopography {
+    pub api_key: String,
+}
+
+impl Default for OpenTopography {
+    fn default() -> OpenTopography {
+        OpenTopography {
+            api_key: std::env::var("OPENTOPOGRAPHY_API_KEY").unwrap_or_default(),
+        }
+    }
+}
+
+impl TileSource for OpenTopography {
+    fn name(&self) -> &str {
+        "opentopography"
+    }
+
+    fn url_template(&self) -> &str {
+        "https://portal.opentopography.org/API/globaldem?demtype=SRTMGL1&west={west}&east={east}&south={south}&north={north}&outputFormat=GTiff&API_Key={api_key}"
+    }
+
+    fn file_extension(&self) -> &str {
+        "tiff"
+    }
+
+    fn max_zoom(&self) -> u32 {
+        16
+    }
+
+    fn attribution(&self) -> &str {
+        "OpenTopography, NASA SRTM"
+    }
+
+    fn heightmap_format(&self) -> HeightmapFormat {
+        HeightmapFormat::GeoTiff
+    }
+
+    fn tile_url(&self, x: u32, y: u32, z: u32) -> Option<String> {
+        // A pixel of margin around the tile, so that its edges can be interpolated
+        let margin = 1. / 3600.;
+        let (north, west) = num2deg(x, y, z);
+        let (south, east) = num2deg(x + 1, y + 1, z);
+        let url = self
+            .url_template()
+            .replace("{west}", &(west - margin).to_string())
+            .replace("{east}", &(east + margin).to_string())
+            .replace("{south}", &(south - margin).to_string())
+            .replace("{north}", &(north + margin).to_string())
+            .replace("{api_key}", &self.api_key);
+        Some(url)
+    }
+}
+
+/// The contents of a tile, or of its nearest available ancestor.
+#[derive(Clone)]
+pub struct TileContents {
+    pub data: Vec<u8>,
+    /// The tile the contents come from, the ancestor's when `levels_up > 0`.
+    pub tile: (u32, u32, u32),
+    /// How many zoom levels above the tile the contents come from.
+    pub levels_up: u32,
+    /// The part of the contents covering the tile, as texture coordinates.
+    pub uv_offset: [f32; 2],
+    pub uv_scale: f32,
+}
+
+impl TileContents {
+    /// Maps texture coordinates within the tile to the contents.
+    pub fn uv(&self, uv: [f32; 2]) -> [f32; 2] {
+        [
+            self.uv_offset[0] + uv[0] * self.uv_scale,
+            self.uv_offset[1] + uv[1] * self.uv_scale,
+        ]
+    }
+}
+
+/// The sources the viewers load their tiles from.
+#[derive(Clone)]
+pub struct TileSources {
+    pub imagery: Arc<dyn TileSource>,
+    pub elevation: Arc<dyn TileSource>,
+    pub cache: Arc<dyn TileCache>,
+    pub downloader: Arc<Downloader>,
+    pub requests: Arc<TileRequests>,
+}
+
+impl Default for TileSources {
+    fn default() -> TileSources {
+        TileSources {
+            imagery: Arc::new(ArcGisImagery),
+            elevation: Arc::new(ArcGisElevation),
+            cache: Arc::new(FileCache::new(&default_cache_dir())),
+            downloader: Arc::new(Downloader::default()),
+            requests: Arc::new(TileRequests::default()),
+        }
+    }
+}
+
+impl TileSources {
+    /// Builds the sources from the command line arguments:
+    /// - `--imagery <file>` and `--elevation <file>` read the layer from a
+    ///   local archive instead of downloading it
+    /// - `--elevation opentopography` and `--elevation terrarium` download the
+    ///   elevation from OpenTopography or Mapzen's Terrarium tiles instead of
+    ///   ArcGIS. Archives with PNG elevation give its encoding in their
+    ///   `encoding` metadata, `mapbox` or `terrarium`
+    /// - `--elevation-url <url template>` downloads the elevation from another
+    ///   server, its format is picked from the extension, e.g. `.terrain` for
+    ///   Cesium quantized-mesh tiles, whose `{y}` is counted from the south
+    /// - `--cache-dir <directory>` caches downloads there instead of the
+    ///   default cache directory
+    /// - `--mbtiles-cache <directory>` caches downloads in one MBTiles file
+    ///   per layer instead of one file per tile, a relative directory being
+    ///   taken from the cache directory
+    /// - `--max-connections <n>` and `--requests-per-second <n>` limit the
+    ///   requests made to each tile server
+    /// - `--cache-size <megabytes>` bounds the file cache, deleting the least
+    ///   recently used tiles past it, not with `--mbtiles-cache`
+    /// - `--pin <west,south,east,north,min_zoom,max_zoom>` keeps a region's
+    ///   tiles in the file cache whatever its size, can be repeated
+    /// - `--offline` only uses cached and archived tiles
+    pub fn from_args() -> TileSources {
+        TileSources::from_arg_list(std::env::args().skip(1))
+    }
+
+    /// Builds the sources from arguments as described in `from_args`.
+    pub fn from_arg_list(args: impl IntoIterator<Item = String>) -> TileSources {
+        let mut sources = TileSources::default();
+        let mut download_options = DownloadOptions::default();
+        let mut file_cache = FileCache::new(&default_cache_dir());
+        let mut cache_dir_given = false;
+        let mut mbtiles_cache = None;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--offline" {
+                download_options.offline = true;
+                continue;
+            }
+            let value = args
+                .next()
+                .unwrap_or_else(|| panic!("Missing value for {}", arg));
+            match arg.as_str() {
+                "--imagery" => sources.imagery = open_archive(&value),
+                "--elevation" => {
+                    sources.elevation = match value.as_str() {
+                        "opentopography" => Arc::new(OpenTopography::default()),
+                        "terrarium" => Arc::new(terrarium_elevation()),
+                        _ => open_archive(&value),
+                    }
+                }
+                "--elevation-url" => sources.elevation = Arc::new(elevation_url_source(&value)),
+                "--cache-dir" => {
+                    file_cache.directory = value;
+                    cache_dir_given = true;
+                }
+                "--mbtiles-cache" => mbtiles_cache = Some(value),
+                "--max-connections" => {
+                    download_options.max_connections_per_host = parse_arg(&arg, &value)
+                }
+                "--requests-per-second" => {
+                    download_options.requests_per_second = parse_arg(&arg, &value)
+                }
+                "--cache-size" => {
+                    let megabytes: u64 = parse_arg(&arg, &value);
+                    file_cache.max_bytes = Some(megabytes * 1024 * 1024);
+                }
+                "--pin" => file_cache.pinned_regions.push(
+                    TileRegion::parse(&value)
+                        .unwrap_or_else(|| panic!("Invalid value {} for {}", value, arg)),
+                ),
+                _ => panic!("Unknown argument {}", arg),
+            }
+        }
+        if !cache_dir_given
+            && file_cache.directory == LEGACY_CACHE_DIR
+            && Path::new(LEGACY_CACHE_DIR).is_dir()
+        {
+            println!(
+                "Using the tile cache in {}, move it to $RUST_MAPS_CACHE_DIR or pass --cache-dir to keep it elsewhere",
+                LEGACY_CACHE_DIR
+            );
+        }
+        sources.downloader = Arc::new(Downloader::new(download_options));
+        sources.cache = match mbtiles_cache {
+            Some(_) if file_cache.max_bytes.is_some() || !file_cache.pinned_regions.is_empty() => {
+                panic!("--cache-size and --pin only apply to the file cache, not --mbtiles-cache")
+            }
+            Some(directory) => {
+                let directory = Path::new(&file_cache.directory).join(directory);
+                Arc::new(MbTilesCache::new(&directory.to_string_lossy()))
+            }
+            None => Arc::new(file_cache),
+        };
+        sources
+    }
+
+    /// Returns a tile of one of the sources, sharing the fetch with the other
+    /// requests for the same tile. Offline, a tile that isn't cached is
+    /// replaced by its nearest cached ancestor.
+    pub async fn get_tile(
+        &self,
+        source: &Arc<dyn TileSource>,
+        x: u32,
+        y: u32,
+        z: u32,
+    ) -> Result<TileContents, TileError> {
+        let mut levels_up = 0;
+        loop {
+            let (ancestor_x, ancestor_y, ancestor_z) = tile_ancestor(x, y, z, levels_up);
+            let data = self
+                .requests
+                .get_tile(
+                    source,
+                    &self.cache,
+                    &self.downloader,
+                    ancestor_x,
+                    ancestor_y,
+                    ancestor_z,
+                )
+                .await;
+            match data {
+                Ok(data) => {
+                    let n = 2_u32.pow(levels_up);
+                    let uv_scale = 1. / n as f32;
+                    return Ok(TileContents {
+                        data,
+                        tile: (ancestor_x, ancestor_y, ancestor_z),
+                        levels_up,
+                        uv_offset: [(x % n) as f32 * uv_scale, (y % n) as f32 * uv_scale],
+                        uv_scale,
+                    });
+                }
+                Err(TileError::Offline) if levels_up < z => levels_up += 1,
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Returns a tile of one of the sources if it's archived or cached,
+    /// without downloading it, however stale it is.
+    pub fn get_cached_tile(
+        &self,
+        source: &Arc<dyn TileSource>,
+        x: u32,
+        y: u32,
+        z: u32,
+    ) -> Option<TileContents> {
+        if z > source.max_zoom() {
+            return None;
+        }
+        let data = match source.read_local(x, y, z).ok()? {
+            Some(data) => data,
+            None => {
+                let cached = self.cache.get(&**source, x, y, z).ok()??;
+                Some(cached.data).filter(|data| is_valid_tile(source.file_extension(), data))?
+            }
+        };
+        Some(TileContents {
+            data,
+            tile: (x, y, z),
+            levels_up: 0,
+            uv_offset: [0., 0.],
+            uv_scale: 1.,
+        })
+    }
+}
+
+pub fn parse_arg<T: std::str::FromStr>(arg: &str, value: &str) -> T {
+    value
+        .parse()
+        .unwrap_or_else(|_| panic!("Invalid value {} for {}", value, arg))
+}
+
+/// Opens a tile archive as a source, its type is picked from the extension.
+pub fn open_archive(filename: &str) -> Arc<dyn TileSource> {
+    if filename.ends_with(".mbtiles") {
+        let source = MbTilesSource::open(filename)
+            .unwrap_or_else(|error| panic!("Failed to open {}: {}", filename, error));
+        Arc::new(source)
+    } else if filename.ends_with(".pmtiles") {
+        let source = PmTilesSource::open(filename)
+            .unwrap_or_else(|error| panic!("Failed to open {}: {}", filename, error));
+        Arc::new(source)
+    } else {
+        panic!("Unsupported tile archive {}", filename)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subdomains() -> Vec<String> {
+        vec!["a".to_string(), "b".to_string(), "c".to_string()]
+    }
+
+    #[test]
+    fn fills_xyz_and_tms_rows() {
+        let template = "https://tiles.example.com/{z}/{x}/{y}.png";
+        assert_eq!(
+            fill_url_template(template, 3, 5, 3, TileScheme::Xyz, &[]),
+            "https://tiles.example.com/3/3/5.png"
+        );
+        assert_eq!(
+            fill_url_template(template, 3, 5, 3, TileScheme::Tms, &[]),
+            "https://tiles.example.com/3/3/2.png"
+        );
+        let template = "https://tiles.example.com/{z}/{x}/{-y}.png";
+        assert_eq!(
+            fill_url_template(template, 3, 5, 3, TileScheme::Xyz, &[]),
+            "https://tiles.example.com/3/3/2.png"
+        );
+    }
+
+    #[test]
+    fn fills_subdomains() {
+        let template = "https://{s}.tiles.example.com/{z}/{x}/{y}.png";
+        assert_eq!(
+            fill_url_template(template, 3, 5, 3, TileScheme::Xyz, &subdomains()),
+            "https://c.tiles.example.com/3/3/5.png"
+        );
+        assert_eq!(
+            fill_url_template(template, 4, 5, 3, TileScheme::Xyz, &subdomains()),
+            "https://a.tiles.example.com/3/4/5.png"
+        );
+        assert_eq!(
+            fill_url_template(template, 3, 5, 3, TileScheme::Xyz, &[]),
+            "https://tiles.example.com/3/3/5.png"
+        );
+    }
+
+    #[test]
+    fn fills_quadkeys() {
+        let template = "https://t{s}.tiles.example.com/tiles/a{quadkey}.jpeg";
+        let subdomains: Vec<String> = (0..4).map(|i| i.to_string()).collect();
+        assert_eq!(
+            fill_url_template(template, 3, 5, 3, TileScheme::Xyz, &subdomains),
+            "https://t3.tiles.example.com/tiles/a213.jpeg"
+        );
+        assert_eq!(
+            fill_url_template(template, 2, 5, 3, TileScheme::Xyz, &subdomains),
+            "https://t2.tiles.example.com/tiles/a212.jpeg"
+        );
+        assert_eq!(
+            fill_url_template(template, 2, 5, 3, TileScheme::Xyz, &[]),
+            "https://t.tiles.example.com/tiles/a212.jpeg"
+        );
+    }
 }
@@ -0,0 +1,399 @@
+use super::heightmap::HeightmapFormat;
+use super::map_services::*;
+use super::tile_cache::*;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+// MBTiles stores a whole tileset in a single SQLite file
+// From: https://github.com/mapbox/mbtiles-spec/blob/master/1.3/spec.md
+
+/// The `format` metadata for tiles with a file extension, which the spec
+/// only allows "jpg" for JPEGs.
+fn mbtiles_format(file_extension: &str) -> &str {
+    match file_extension {
+        "jpeg" => "jpg",
+        extension => extension,
+    }
+}
+
+/// A tileset stored in an MBTiles file. MBTiles numbers the rows as in TMS,
+/// the rows are flipped here so that callers keep using slippy map rows.
+pub struct MbTiles {
+    connection: Mutex<Connection>,
+}
+
+impl MbTiles {
+    /// Opens an existing MBTiles file for reading.
+    pub fn open(filename: &str) -> Result<MbTiles, rusqlite::Error> {
+        let connection = Connection::open_with_flags(filename, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        Ok(MbTiles {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Opens an MBTiles file for reading and writing, creating it if needed.
+    /// Files created here also get a `tile_cache_metadata` table, outside of
+    /// the spec, to keep what the tile server told us about each tile.
+    pub fn create(filename: &str) -> Result<MbTiles, rusqlite::Error> {
+        let connection = Connection::open(filename)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS metadata (name TEXT, value TEXT);
+             CREATE UNIQUE INDEX IF NOT EXISTS name ON metadata (name);
+             CREATE TABLE IF NOT EXISTS tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, tile_data BLOB);
+             CREATE UNIQUE INDEX IF NOT EXISTS tile_index ON tiles (zoom_level, tile_column, tile_row);
+             CREATE TABLE IF NOT EXISTS tile_cache_metadata (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER, etag TEXT, last_modified TEXT, max_age INTEGER, fetched_at INTEGER);
+             CREATE UNIQUE INDEX IF NOT EXISTS tile_cache_metadata_index ON tile_cache_metadata (zoom_level, tile_column, tile_row);",
+        )?;
+        Ok(MbTiles {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    pub fn metadata(&self, name: &str) -> Result<Option<String>, rusqlite::Error> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT value FROM metadata WHERE name = ?",
+                params![name],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    pub fn set_metadata(&self, name: &str, value: &str) -> Result<(), rusqlite::Error> {
+        self.connection.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO metadata (name, value) VALUES (?, ?)",
+            params![name, value],
+        )?;
+        Ok(())
+    }
+
+    pub fn max_zoom_level(&self) -> Result<Option<u32>, rusqlite::Error> {
+        self.connection.lock().unwrap().query_row(
+            "SELECT MAX(zoom_level) FROM tiles",
+            params![],
+            |row| row.get(0),
+        )
+    }
+
+    /// The number of tiles and their total size for each zoom level.
+    pub fn zoom_level_sizes(&self) -> Result<Vec<(u32, u64, u64)>, rusqlite::Error> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT zoom_level, COUNT(*), SUM(LENGTH(tile_data)) FROM tiles GROUP BY zoom_level ORDER BY zoom_level",
+        )?;
+        let sizes = statement
+            .query_map(params![], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get::<_, i64>(1)? as u64,
+                    row.get::<_, i64>(2)? as u64,
+                ))
+            })?
+            .collect();
+        sizes
+    }
+
+    pub fn read_tile(&self, x: u32, y: u32, z: u32) -> Result<Option<Vec<u8>>, rusqlite::Error> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT tile_data FROM tiles WHERE zoom_level = ? AND tile_column = ? AND tile_row = ?",
+                params![z, x, TileScheme::Tms.row(y, z)],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    pub fn write_tile(&self, x: u32, y: u32, z: u32, data: &[u8]) -> Result<(), rusqlite::Error> {
+        self.connection.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO tiles (zoom_level, tile_column, tile_row, tile_data) VALUES (?, ?, ?, ?)",
+            params![z, x, TileScheme::Tms.row(y, z), data],
+        )?;
+        Ok(())
+    }
+
+    pub fn read_tile_metadata(
+        &self,
+        x: u32,
+        y: u32,
+        z: u32,
+    ) -> Result<Option<TileMetadata>, rusqlite::Error> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT etag, last_modified, max_age, fetched_at FROM tile_cache_metadata WHERE zoom_level = ? AND tile_column = ? AND tile_row = ?",
+                params![z, x, TileScheme::Tms.row(y, z)],
+                |row| {
+                    Ok(TileMetadata {
+                        etag: row.get(0)?,
+                        last_modified: row.get(1)?,
+                        max_age: row.get::<_, Option<i64>>(2)?.map(|max_age| max_age as u64),
+                        fetched_at: row.get::<_, i64>(3)? as u64,
+                    })
+                },
+            )
+            .optional()
+    }
+
+    pub fn write_tile_metadata(
+        &self,
+        x: u32,
+        y: u32,
+        z: u32,
+        metadata: &TileMetadata,
+    ) -> Result<(), rusqlite::Error> {
+        self.connection.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO tile_cache_metadata (zoom_level, tile_column, tile_row, etag, last_modified, max_age, fetched_at) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            params![
+                z,
+                x,
+                TileScheme::Tms.row(y, z),
+                metadata.etag,
+                metadata.last_modified,
+                metadata.max_age.map(|max_age| max_age as i64),
+                metadata.fetched_at as i64
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+/// Caches each source in its own `<name>.mbtiles` file inside a directory.
+pub struct MbTilesCache {
+    directory: String,
+    archives: Mutex<HashMap<String, Arc<MbTiles>>>,
+}
+
+impl MbTilesCache {
+    pub fn new(directory: &str) -> MbTilesCache {
+        MbTilesCache {
+            directory: directory.to_string(),
+            archives: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn archive(&self, source: &dyn TileSource) -> Result<Arc<MbTiles>, TileError> {
+        let mut archives = self.archives.lock().unwrap();
+        if let Some(archive) = archives.get(source.name()) {
+            return Ok(archive.clone());
+        }
+
+        std::fs::create_dir_all(&self.directory)?;
+        let archive = MbTiles::create(&format!("{}/{}.mbtiles", self.directory, source.name()))?;
+        if archive.metadata("name")?.is_none() {
+            archive.set_metadata("name", source.name())?;
+            archive.set_metadata("format", mbtiles_format(source.file_extension()))?;
+            archive.set_metadata("maxzoom", &source.max_zoom().to_string())?;
+            archive.set_metadata("attribution", source.attribution())?;
+        }
+        let archive = Arc::new(archive);
+        archives.insert(source.name().to_string(), archive.clone());
+        Ok(archive)
+    }
+}
+
+impl TileCache for MbTilesCache {
+    fn get(
+        &self,
+        source: &dyn TileSource,
+        x: u32,
+        y: u32,
+        z: u32,
+    ) -> Result<Option<CachedTile>, TileError> {
+        let archive = self.archive(source)?;
+        let data = match archive.read_tile(x, y, z)? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        // Tiles without metadata are stale, so they get downloaded again
+        let metadata = archive.read_tile_metadata(x, y, z)?.unwrap_or_default();
+        Ok(Some(CachedTile { data, metadata }))
+    }
+
+    fn put(
+        &self,
+        source: &dyn TileSource,
+        x: u32,
+        y: u32,
+        z: u32,
+        data: &[u8],
+        metadata: &TileMetadata,
+    ) -> Result<(), TileError> {
+        let archive = self.archive(source)?;
+        archive.write_tile(x, y, z, data)?;
+        Ok(archive.write_tile_metadata(x, y, z, metadata)?)
+    }
+
+    fn put_metadata(
+        &self,
+        source: &dyn TileSource,
+        x: u32,
+        y: u32,
+        z: u32,
+        metadata: &TileMetadata,
+    ) -> Result<(), TileError> {
+        Ok(self
+            .archive(source)?
+            .write_tile_metadata(x, y, z, metadata)?)
+    }
+
+    fn usage(&self) -> Result<Vec<CacheUsage>, TileError> {
+        let mut usage = Vec::new();
+        let files = match std::fs::read_dir(&self.directory) {
+            Ok(files) => files,
+            Err(_) => return Ok(usage),
+        };
+        let mut filenames: Vec<_> = files.filter_map(|file| Some(file.ok()?.path())).collect();
+        filenames.sort();
+        for filename in filenames {
+            if filename
+                .extension()
+                .map_or(true, |extension| extension != "mbtiles")
+            {
+                continue;
+            }
+            let layer = match filename.file_stem() {
+                Some(stem) => stem.to_string_lossy().to_string(),
+                None => continue,
+            };
+            let archive = MbTiles::open(&filename.to_string_lossy())?;
+            for (zoom, tiles, bytes) in archive.zoom_level_sizes()? {
+                usage.push(CacheUsage {
+                    layer: layer.clone(),
+                    zoom,
+                    tiles,
+                    bytes,
+                });
+            }
+        }
+        Ok(usage)
+    }
+}
+
+/// Serves the tiles of an existing MBTiles file, without any downloads.
+pub struct MbTilesSource {
+    archive: MbTiles,
+    name: String,
+    file_extension: String,
+    max_zoom: u32,
+    attribution: String,
+    heightmap_format: HeightmapFormat,
+}
+
+impl MbTilesSource {
+    pub fn open(filename: &str) -> Result<MbTilesSource, Box<dyn std::error::Error>> {
+        let archive = MbTiles::open(filename)?;
+
+        let name = match archive.metadata("name")? {
+            Some(name) => name,
+            None => Path::new(filename)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_default(),
+        };
+        // Written "jpg" by the spec, some writers use "jpeg" though
+        let file_extension = match archive.metadata("format")?.as_deref() {
+            Some("jpeg") => "jpg".to_string(),
+            Some(format) => format.to_string(),
+            None => "png".to_string(),
+        };
+        let max_zoom = match archive.metadata("maxzoom")? {
+            Some(max_zoom) => max_zoom.parse()?,
+            None => archive.max_zoom_level()?.unwrap_or(0),
+        };
+        let attribution = archive.metadata("attribution")?.unwrap_or_default();
+        let heightmap_format =
+            HeightmapFormat::for_tiles(&file_extension, archive.metadata("encoding")?.as_deref());
+
+        Ok(MbTilesSource {
+            archive,
+            name,
+            file_extension,
+            max_zoom,
+            attribution,
+            heightmap_format,
+        })
+    }
+}
+
+impl TileSource for MbTilesSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn file_extension(&self) -> &str {
+        &self.file_extension
+    }
+
+    fn max_zoom(&self) -> u32 {
+        self.max_zoom
+    }
+
+    fn attribution(&self) -> &str {
+        &self.attribution
+    }
+
+    fn heightmap_format(&self) -> HeightmapFormat {
+        self.heightmap_format
+    }
+
+    fn read_local(&self, x: u32, y: u32, z: u32) -> Result<Option<Vec<u8>>, TileError> {
+        Ok(self.archive.read_tile(x, y, z)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let directory = tempfile::tempdir().unwrap();
+        let cache = MbTilesCache::new(directory.path().to_str().unwrap());
+        let source = UrlTemplateSource {
+            name: "imagery".to_string(),
+            file_extension: "jpeg".to_string(),
+            max_zoom: 5,
+            ..Default::default()
+        };
+        let metadata = TileMetadata {
+            etag: Some("\"1\"".to_string()),
+            last_modified: None,
+            max_age: Some(60),
+            fetched_at: 1000,
+        };
+        cache.put(&source, 1, 0, 2, b"tile", &metadata).unwrap();
+
+        let tile = cache.get(&source, 1, 0, 2).unwrap().unwrap();
+        assert_eq!(tile.data, b"tile");
+        assert_eq!(tile.metadata, metadata);
+        assert!(cache.get(&source, 1, 3, 2).unwrap().is_none());
+        let usage = cache.usage().unwrap();
+        assert_eq!(usage.len(), 1);
+        assert_eq!((usage[0].zoom, usage[0].tiles, usage[0].bytes), (2, 1, 4));
+
+        // Stored with TMS rows, counted from the south
+        let filename = directory.path().join("imagery.mbtiles");
+        let archive = MbTiles::open(filename.to_str().unwrap()).unwrap();
+        let row: u32 = archive
+            .connection
+            .lock()
+            .unwrap()
+            .query_row("SELECT tile_row FROM tiles", params![], |row| row.get(0))
+            .unwrap();
+        assert_eq!(row, 3);
+        assert_eq!(archive.metadata("format").unwrap().as_deref(), Some("jpg"));
+
+        let source = MbTilesSource::open(filename.to_str().unwrap()).unwrap();
+        assert_eq!(source.name(), "imagery");
+        assert_eq!(source.file_extension(), "jpg");
+        assert_eq!(source.max_zoom(), 5);
+        assert_eq!(source.read_local(1, 0, 2).unwrap().unwrap(), b"tile");
+    }
+}
@@ -0,0 +1,389 @@
+use super::downloader::{DownloadOptions, Downloader};
+use super::map_services::*;
+use super::tile_cache::{FileCache, TileCache};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+// A tile server on localhost for tests, so that the download, cache and retry
+// paths can be exercised without a network. Tiles are served at
+// `/{z}/{x}/{y}`, with an ETag so that they can be revalidated.
+
+#[derive(Debug, Clone)]
+pub enum MockResponse {
+    Tile(Vec<u8>),
+    /// An empty response with this status.
+    Status(u16),
+    /// The response, sent after waiting.
+    Slow(Duration, Box<MockResponse>),
+    /// Announces the whole tile but closes the connection half way through.
+    Truncated(Vec<u8>),
+}
+
+type Routes = HashMap<(u32, u32, u32), VecDeque<MockResponse>>;
+
+pub struct MockTileServer {
+    port: u16,
+    routes: Arc<Mutex<Routes>>,
+    requests: Arc<Mutex<Vec<(u32, u32, u32)>>>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for MockTileServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl MockTileServer {
+    pub async fn start() -> MockTileServer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let routes = Arc::new(Mutex::new(Routes::new()));
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let task = {
+            let routes = routes.clone();
+            let requests = requests.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(serve(stream, routes.clone(), requests.clone()));
+                }
+            })
+        };
+        MockTileServer {
+            port,
+            routes,
+            requests,
+            task,
+        }
+    }
+
+    pub fn url_template(&self) -> String {
+        format!("http://127.0.0.1:{}/{{z}}/{{x}}/{{y}}", self.port)
+    }
+
+    /// A source downloading its tiles from this server.
+    pub fn source(&self, name: &str, file_extension: &str) -> UrlTemplateSource {
+        UrlTemplateSource {
+            name: name.to_string(),
+            url_template: self.url_template(),
+            file_extension: file_extension.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Queues the responses to the next requests for a tile, the last one is
+    /// repeated. Tiles without responses are 404s.
+    pub fn respond(&self, x: u32, y: u32, z: u32, responses: Vec<MockResponse>) {
+        self.routes
+            .lock()
+            .unwrap()
+            .insert((x, y, z), responses.into_iter().collect());
+    }
+
+    /// How many times a tile was requested.
+    pub fn requests(&self, x: u32, y: u32, z: u32) -> usize {
+        let requests = self.requests.lock().unwrap();
+        requests.iter().filter(|tile| **tile == (x, y, z)).count()
+    }
+}
+
+/// A 4×4 gray JPEG.
+pub fn synthetic_jpeg() -> Vec<u8> {
+    let image = image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+        4,
+        4,
+        image::Rgb([128, 128, 128]),
+    ));
+    let mut data = Vec::new();
+    image
+        .write_to(&mut data, image::ImageOutputFormat::Jpeg(90))
+        .unwrap();
+    data
+}
+
+/// A LERC2 v3 header announcing a 64 byte blob. It passes the checks of
+/// `is_valid_tile` but doesn't decode.
+pub fn synthetic_lerc() -> Vec<u8> {
+    let mut data = b"Lerc2 ".to_vec();
+    data.extend_from_slice(&3_i32.to_le_bytes());
+    data.extend_from_slice(&0_u32.to_le_bytes());
+    // nRows, nCols, numValidPixel, microBlockSize, blobSize
+    for value in &[2_i32, 2, 4, 8, 64] {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+    data.resize(64, 0);
+    data
+}
+
+/// Retries and times out quickly, so that the tests don't wait.
+pub fn test_downloader(options: DownloadOptions) -> Downloader {
+    Downloader::new(DownloadOptions {
+        initial_backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(10),
+        timeout: Duration::from_millis(500),
+        ..options
+    })
+}
+
+/// A server, a source downloading from it, and an empty file cache.
+pub struct Fixture {
+    pub server: MockTileServer,
+    pub source: Arc<dyn TileSource>,
+    pub cache: Arc<dyn TileCache>,
+    pub downloader: Arc<Downloader>,
+    /// Holds the cache's directory until the test ends.
+    _directory: tempfile::TempDir,
+}
+
+impl Fixture {
+    pub async fn start(name: &str, file_extension: &str, options: DownloadOptions) -> Fixture {
+        let server = MockTileServer::start().await;
+        let source = Arc::new(server.source(name, file_extension));
+        let directory = tempfile::tempdir().unwrap();
+        Fixture {
+            server,
+            source,
+            cache: Arc::new(FileCache::new(directory.path().to_str().unwrap())),
+            downloader: Arc::new(test_downloader(options)),
+            _directory: directory,
+        }
+    }
+
+    pub async fn get_tile(&self, x: u32, y: u32, z: u32) -> Result<Vec<u8>, TileError> {
+        get_tile(&*self.source, &*self.cache, &self.downloader, x, y, z).await
+    }
+}
+
+fn etag(data: &[u8]) -> String {
+    let sum = data.iter().fold(0_u32, |sum, byte| {
+        sum.wrapping_mul(31).wrapping_add(*byte as u32)
+    });
+    format!("\"{}-{:x}\"", data.len(), sum)
+}
+
+async fn serve(
+    mut stream: TcpStream,
+    routes: Arc<Mutex<Routes>>,
+    requests: Arc<Mutex<Vec<(u32, u32, u32)>>>,
+) {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => request.extend_from_slice(&buffer[..n]),
+        }
+    }
+    let request = String::from_utf8_lossy(&request).to_string();
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    let tile: Vec<u32> = path
+        .split('/')
+        .filter_map(|part| part.parse().ok())
+        .collect();
+    let if_none_match = request.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name.eq_ignore_ascii_case("if-none-match") {
+            Some(value.trim().to_string())
+        } else {
+            None
+        }
+    });
+
+    let response = match tile.as_slice() {
+        &[z, x, y] => {
+            requests.lock().unwrap().push((x, y, z));
+            let mut routes = routes.lock().unwrap();
+            match routes.get_mut(&(x, y, z)) {
+                Some(responses) if responses.len() > 1 => responses.pop_front(),
+                Some(responses) => responses.front().cloned(),
+                None => None,
+            }
+        }
+        _ => None,
+    };
+    let mut response = response.unwrap_or(MockResponse::Status(404));
+    while let MockResponse::Slow(delay, slow_response) = response {
+        tokio::time::sleep(delay).await;
+        response = *slow_response;
+    }
+
+    let (status, body, sent, etag) = match response {
+        MockResponse::Tile(data) if if_none_match == Some(etag(&data)) => {
+            let etag = etag(&data);
+            (304, Vec::new(), 0, Some(etag))
+        }
+        MockResponse::Tile(data) => {
+            let length = data.len();
+            let etag = etag(&data);
+            (200, data, length, Some(etag))
+        }
+        MockResponse::Truncated(data) => {
+            let length = data.len() / 2;
+            (200, data, length, None)
+        }
+        MockResponse::Status(status) => (status, Vec::new(), 0, None),
+        MockResponse::Slow(..) => unreachable!(),
+    };
+    let mut head = format!(
+        "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status,
+        body.len()
+    );
+    if let Some(etag) = etag {
+        head += &format!("ETag: {}\r\n", etag);
+    }
+    head += "\r\n";
+    let _ = stream.write_all(head.as_bytes()).await;
+    let _ = stream.write_all(&body[..sent]).await;
+    let _ = stream.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tile_cache::TileMetadata;
+
+    #[tokio::test]
+    async fn downloads_and_caches_tiles() {
+        let fixture = Fixture::start("imagery", "jpeg", DownloadOptions::default()).await;
+        fixture
+            .server
+            .respond(1, 2, 3, vec![MockResponse::Tile(synthetic_jpeg())]);
+
+        for _ in 0..2 {
+            let data = fixture.get_tile(1, 2, 3).await.unwrap();
+            assert_eq!(data, synthetic_jpeg());
+        }
+        assert_eq!(fixture.server.requests(1, 2, 3), 1);
+    }
+
+    #[tokio::test]
+    async fn retries_throttled_and_failed_requests() {
+        let fixture = Fixture::start("topo", "lerc", DownloadOptions::default()).await;
+        fixture.server.respond(
+            1,
+            2,
+            3,
+            vec![
+                MockResponse::Status(500),
+                MockResponse::Status(429),
+                MockResponse::Tile(synthetic_lerc()),
+            ],
+        );
+
+        let data = fixture.get_tile(1, 2, 3).await.unwrap();
+        assert_eq!(data, synthetic_lerc());
+        assert_eq!(fixture.server.requests(1, 2, 3), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_last_retry() {
+        let options = DownloadOptions {
+            max_retries: 2,
+            ..Default::default()
+        };
+        let fixture = Fixture::start("imagery", "jpeg", options).await;
+        fixture
+            .server
+            .respond(1, 2, 3, vec![MockResponse::Status(503)]);
+
+        let result = fixture.get_tile(1, 2, 3).await;
+        assert!(matches!(result, Err(TileError::HttpStatus(status)) if status == 503));
+        assert_eq!(fixture.server.requests(1, 2, 3), 3);
+    }
+
+    #[tokio::test]
+    async fn missing_tiles_are_not_retried() {
+        let fixture = Fixture::start("imagery", "jpeg", DownloadOptions::default()).await;
+
+        let result = fixture.get_tile(1, 2, 3).await;
+        assert!(matches!(result, Err(TileError::NotFound)));
+        assert_eq!(fixture.server.requests(1, 2, 3), 1);
+    }
+
+    #[tokio::test]
+    async fn truncated_and_invalid_tiles_are_not_cached() {
+        let fixture = Fixture::start("imagery", "jpeg", DownloadOptions::default()).await;
+        let (server, source, cache) = (&fixture.server, &*fixture.source, &fixture.cache);
+        server.respond(1, 2, 3, vec![MockResponse::Truncated(synthetic_jpeg())]);
+        server.respond(4, 5, 6, vec![MockResponse::Tile(b"<html>".to_vec())]);
+
+        let truncated = fixture.get_tile(1, 2, 3).await;
+        assert!(truncated.is_err());
+        let invalid = fixture.get_tile(4, 5, 6).await;
+        assert!(matches!(invalid, Err(TileError::Decode(_))));
+        assert!(cache.get(source, 1, 2, 3).unwrap().is_none());
+        assert!(cache.get(source, 4, 5, 6).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn slow_responses_time_out() {
+        let options = DownloadOptions {
+            max_retries: 0,
+            ..Default::default()
+        };
+        let fixture = Fixture::start("imagery", "jpeg", options).await;
+        fixture.server.respond(
+            1,
+            2,
+            3,
+            vec![MockResponse::Slow(
+                Duration::from_secs(2),
+                Box::new(MockResponse::Tile(synthetic_jpeg())),
+            )],
+        );
+
+        let result = fixture.get_tile(1, 2, 3).await;
+        assert!(matches!(result, Err(TileError::Network(_))));
+    }
+
+    #[tokio::test]
+    async fn stale_tiles_are_revalidated() {
+        let fixture = Fixture::start("imagery", "jpeg", DownloadOptions::default()).await;
+        let (server, source, cache) = (&fixture.server, &*fixture.source, &fixture.cache);
+        server.respond(1, 2, 3, vec![MockResponse::Tile(synthetic_jpeg())]);
+        fixture.get_tile(1, 2, 3).await.unwrap();
+        let metadata = cache.get(source, 1, 2, 3).unwrap().unwrap().metadata;
+        assert!(metadata.etag.is_some());
+        let stale = TileMetadata {
+            fetched_at: 0,
+            ..metadata
+        };
+        cache.put_metadata(source, 1, 2, 3, &stale).unwrap();
+
+        let data = fixture.get_tile(1, 2, 3).await.unwrap();
+        assert_eq!(data, synthetic_jpeg());
+        assert_eq!(server.requests(1, 2, 3), 2);
+        let metadata = cache.get(source, 1, 2, 3).unwrap().unwrap().metadata;
+        assert!(metadata.fetched_at > 0);
+    }
+
+    #[tokio::test]
+    async fn offline_tiles_fall_back_to_ancestors() {
+        let fixture = Fixture::start("imagery", "jpeg", DownloadOptions::default()).await;
+        fixture
+            .server
+            .respond(2, 3, 4, vec![MockResponse::Tile(synthetic_jpeg())]);
+        fixture.get_tile(2, 3, 4).await.unwrap();
+
+        let sources = TileSources {
+            imagery: fixture.source.clone(),
+            elevation: fixture.source.clone(),
+            cache: fixture.cache.clone(),
+            downloader: Arc::new(test_downloader(DownloadOptions {
+                offline: true,
+                ..Default::default()
+            })),
+            requests: Default::default(),
+        };
+        let contents = sources.get_tile(&fixture.source, 5, 7, 5).await.unwrap();
+        assert_eq!(contents.data, synthetic_jpeg());
+        assert_eq!(contents.levels_up, 1);
+        assert_eq!(contents.uv_offset, [0.5, 0.5]);
+        assert_eq!(fixture.server.requests(5, 7, 5), 0);
+    }
+}
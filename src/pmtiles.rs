@@ -0,0 +1,475 @@
+use super::heightmap::HeightmapFormat;
+use super::map_services::*;
+use flate2::read::GzDecoder;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Mutex;
+
+// PMTiles stores a whole tileset in a single file, tiles are found through
+// directories indexed by the tile's position on a Hilbert curve
+// From: https://github.com/protomaps/PMTiles/blob/main/spec/v3/spec.md
+
+const HEADER_LENGTH: usize = 127;
+const MAX_DIRECTORY_DEPTH: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Compression {
+    Unknown,
+    None,
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+impl Compression {
+    fn from_byte(byte: u8) -> Compression {
+        match byte {
+            1 => Compression::None,
+            2 => Compression::Gzip,
+            3 => Compression::Brotli,
+            4 => Compression::Zstd,
+            _ => Compression::Unknown,
+        }
+    }
+
+    fn decompress(&self, data: Vec<u8>) -> Result<Vec<u8>, TileError> {
+        match self {
+            Compression::None | Compression::Unknown => Ok(data),
+            Compression::Gzip => {
+                let mut decompressed = Vec::new();
+                GzDecoder::new(&data[..]).read_to_end(&mut decompressed)?;
+                Ok(decompressed)
+            }
+            _ => Err(TileError::Decode(format!(
+                "unsupported PMTiles compression {:?}",
+                self
+            ))),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Header {
+    root_directory_offset: u64,
+    root_directory_length: u64,
+    metadata_offset: u64,
+    metadata_length: u64,
+    leaf_directories_offset: u64,
+    tile_data_offset: u64,
+    internal_compression: Compression,
+    tile_compression: Compression,
+    tile_type: u8,
+    max_zoom: u8,
+}
+
+impl Header {
+    fn parse(bytes: &[u8]) -> Result<Header, TileError> {
+        if bytes.len() < HEADER_LENGTH || &bytes[0..7] != b"PMTiles" {
+            return Err(TileError::Decode("not a PMTiles archive".to_string()));
+        }
+        if bytes[7] != 3 {
+            return Err(TileError::Decode(format!(
+                "unsupported PMTiles version {}",
+                bytes[7]
+            )));
+        }
+        let u64_at = |offset: usize| {
+            let mut value = [0; 8];
+            value.copy_from_slice(&bytes[offset..offset + 8]);
+            u64::from_le_bytes(value)
+        };
+        Ok(Header {
+            root_directory_offset: u64_at(8),
+            root_directory_length: u64_at(16),
+            metadata_offset: u64_at(24),
+            metadata_length: u64_at(32),
+            leaf_directories_offset: u64_at(40),
+            tile_data_offset: u64_at(56),
+            internal_compression: Compression::from_byte(bytes[97]),
+            tile_compression: Compression::from_byte(bytes[98]),
+            tile_type: bytes[99],
+            max_zoom: bytes[101],
+        })
+    }
+
+    fn file_extension(&self) -> Option<&'static str> {
+        match self.tile_type {
+            1 => Some("mvt"),
+            2 => Some("png"),
+            3 => Some("jpeg"),
+            4 => Some("webp"),
+            5 => Some("avif"),
+            _ => None,
+        }
+    }
+}
+
+/// A directory entry either points to a run of identical tiles or, when
+/// `run_length` is 0, to a leaf directory.
+#[derive(Debug, Clone)]
+struct Entry {
+    tile_id: u64,
+    offset: u64,
+    length: u64,
+    run_length: u64,
+}
+
+fn read_varint(bytes: &[u8], position: &mut usize) -> Result<u64, TileError> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *bytes
+            .get(*position)
+            .ok_or_else(|| TileError::Decode("truncated PMTiles directory".to_string()))?;
+        *position += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+        if shift >= 64 {
+            return Err(TileError::Decode(
+                "invalid varint in PMTiles directory".to_string(),
+            ));
+        }
+    }
+}
+
+/// Directories are stored column by column: the tile ids as deltas, then the
+/// run lengths, the lengths and the offsets, with an offset of 0 meaning the
+/// entry directly follows the previous one.
+fn parse_directory(bytes: &[u8]) -> Result<Vec<Entry>, TileError> {
+    let mut position = 0;
+    let n_entries = read_varint(bytes, &mut position)? as usize;
+    let mut entries = Vec::with_capacity(n_entries);
+
+    let mut tile_id = 0;
+    for _ in 0..n_entries {
+        tile_id += read_varint(bytes, &mut position)?;
+        entries.push(Entry {
+            tile_id,
+            offset: 0,
+            length: 0,
+            run_length: 0,
+        });
+    }
+    for entry in entries.iter_mut() {
+        entry.run_length = read_varint(bytes, &mut position)?;
+    }
+    for entry in entries.iter_mut() {
+        entry.length = read_varint(bytes, &mut position)?;
+    }
+    for i in 0..n_entries {
+        let offset = read_varint(bytes, &mut position)?;
+        entries[i].offset = if offset == 0 && i > 0 {
+            entries[i - 1].offset + entries[i - 1].length
+        } else {
+            offset.saturating_sub(1)
+        };
+    }
+    Ok(entries)
+}
+
+fn find_entry(entries: &[Entry], tile_id: u64) -> Option<&Entry> {
+    let index = match entries.binary_search_by_key(&tile_id, |entry| entry.tile_id) {
+        Ok(index) => return Some(&entries[index]),
+        Err(0) => return None,
+        Err(index) => index - 1,
+    };
+    let entry = &entries[index];
+    if entry.run_length == 0 || tile_id - entry.tile_id < entry.run_length {
+        Some(entry)
+    } else {
+        None
+    }
+}
+
+/// Position of a tile on the Hilbert curve of its zoom level, after all the
+/// tiles of the lower zoom levels.
+pub fn tile_id(x: u32, y: u32, z: u32) -> u64 {
+    let mut id = ((1_u64 << (2 * z)) - 1) / 3;
+    let n = 1_u64 << z;
+    let (mut x, mut y) = (x as u64, y as u64);
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = (x & s > 0) as u64;
+        let ry = (y & s > 0) as u64;
+        id += s * s * ((3 * rx) ^ ry);
+        if ry == 0 {
+            if rx == 1 {
+                x = n - 1 - x;
+                y = n - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    id
+}
+
+/// Serves the tiles of a PMTiles v3 archive, reading only the parts of the
+/// file that are needed.
+pub struct PmTilesSource {
+    file: Mutex<File>,
+    header: Header,
+    root_directory: Vec<Entry>,
+    name: String,
+    file_extension: String,
+    attribution: String,
+    heightmap_format: HeightmapFormat,
+}
+
+impl PmTilesSource {
+    pub fn open(filename: &str) -> Result<PmTilesSource, Box<dyn std::error::Error>> {
+        let mut file = File::open(filename)?;
+        let mut header_bytes = [0; HEADER_LENGTH];
+        file.read_exact(&mut header_bytes)?;
+        let header = Header::parse(&header_bytes)?;
+
+        let root_directory = read_range(
+            &mut file,
+            header.root_directory_offset,
+            header.root_directory_length,
+        )?;
+        let root_directory =
+            parse_directory(&header.internal_compression.decompress(root_directory)?)?;
+
+        let metadata = read_range(&mut file, header.metadata_offset, header.metadata_length)?;
+        let metadata: serde_json::Value = if metadata.is_empty() {
+            serde_json::Value::Null
+        } else {
+            serde_json::from_slice(&header.internal_compression.decompress(metadata)?)?
+        };
+        let metadata_str = |key: &str| metadata[key].as_str().map(|value| value.to_string());
+
+        let name = metadata_str("name").unwrap_or_else(|| {
+            Path::new(filename)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_default()
+        });
+        // Formats PMTiles has no tile type for, like LERC, are named in the metadata
+        let file_extension = match header.file_extension() {
+            Some(extension) => extension.to_string(),
+            None => metadata_str("format").unwrap_or("png".to_string()),
+        };
+        let attribution = metadata_str("attribution").unwrap_or_default();
+        let heightmap_format =
+            HeightmapFormat::for_tiles(&file_extension, metadata_str("encoding").as_deref());
+
+        Ok(PmTilesSource {
+            file: Mutex::new(file),
+            header,
+            root_directory,
+            name,
+            file_extension,
+            attribution,
+            heightmap_format,
+        })
+    }
+
+    fn read(&self, offset: u64, length: u64) -> Result<Vec<u8>, TileError> {
+        read_range(&mut *self.file.lock().unwrap(), offset, length)
+    }
+
+    pub fn read_tile(&self, x: u32, y: u32, z: u32) -> Result<Option<Vec<u8>>, TileError> {
+        let tile_id = tile_id(x, y, z);
+        let mut leaf_directory;
+        let mut directory = &self.root_directory;
+
+        for _ in 0..MAX_DIRECTORY_DEPTH {
+            let entry = match find_entry(directory, tile_id) {
+                Some(entry) => entry.clone(),
+                None => return Ok(None),
+            };
+            if entry.run_length > 0 {
+                let data = self.read(self.header.tile_data_offset + entry.offset, entry.length)?;
+                return Ok(Some(self.header.tile_compression.decompress(data)?));
+            }
+            let data = self.read(
+                self.header.leaf_directories_offset + entry.offset,
+                entry.length,
+            )?;
+            leaf_directory = parse_directory(&self.header.internal_compression.decompress(data)?)?;
+            directory = &leaf_directory;
+        }
+        Err(TileError::Decode(
+            "PMTiles directories are nested too deep".to_string(),
+        ))
+    }
+}
+
+fn read_range(file: &mut File, offset: u64, length: u64) -> Result<Vec<u8>, TileError> {
+    let mut data = vec![0; length as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+impl TileSource for PmTilesSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn file_extension(&self) -> &str {
+        &self.file_extension
+    }
+
+    fn max_zoom(&self) -> u32 {
+        self.header.max_zoom as u32
+    }
+
+    fn attribution(&self) -> &str {
+        &self.attribution
+    }
+
+    fn heightmap_format(&self) -> HeightmapFormat {
+        self.heightmap_format
+    }
+
+    fn read_local(&self, x: u32, y: u32, z: u32) -> Result<Option<Vec<u8>>, TileError> {
+        self.read_tile(x, y, z)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn varints(values: &[u64]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for value in values {
+            let mut value = *value;
+            while value >= 0x80 {
+                bytes.push((value & 0x7f) as u8 | 0x80);
+                value >>= 7;
+            }
+            bytes.push(value as u8);
+        }
+        bytes
+    }
+
+    /// Entries as (tile_id, offset + 1 or 0 to follow the previous entry,
+    /// length, run_length).
+    fn directory(entries: &[(u64, u64, u64, u64)]) -> Vec<u8> {
+        let mut values = vec![entries.len() as u64];
+        let mut previous_id = 0;
+        for (tile_id, _, _, _) in entries {
+            values.push(tile_id - previous_id);
+            previous_id = *tile_id;
+        }
+        values.extend(entries.iter().map(|entry| entry.3));
+        values.extend(entries.iter().map(|entry| entry.2));
+        values.extend(entries.iter().map(|entry| entry.1));
+        varints(&values)
+    }
+
+    /// An uncompressed archive of PNG tiles: tile 0, tiles 1 and 2 sharing
+    /// their data, and a leaf directory from tile 5 with only tile 5.
+    fn archive() -> tempfile::NamedTempFile {
+        let leaf_directory = directory(&[(5, 7, 3, 1)]);
+        let root_directory = directory(&[
+            (0, 1, 3, 1),
+            (1, 0, 3, 2),
+            (5, 1, leaf_directory.len() as u64, 0),
+        ]);
+        let tile_data = b"abcdefghi";
+
+        let root_offset = HEADER_LENGTH as u64;
+        let leaf_offset = root_offset + root_directory.len() as u64;
+        let tile_data_offset = leaf_offset + leaf_directory.len() as u64;
+        let mut header = vec![0; HEADER_LENGTH];
+        header[0..7].copy_from_slice(b"PMTiles");
+        header[7] = 3;
+        for (offset, value) in [
+            (8, root_offset),
+            (16, root_directory.len() as u64),
+            (24, leaf_offset),
+            (32, 0),
+            (40, leaf_offset),
+            (48, leaf_directory.len() as u64),
+            (56, tile_data_offset),
+            (64, tile_data.len() as u64),
+        ]
+        .iter()
+        {
+            header[*offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+        }
+        header[97] = 1;
+        header[98] = 1;
+        header[99] = 2;
+        header[101] = 2;
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        for part in [&header[..], &root_directory, &leaf_directory, tile_data].iter() {
+            file.write_all(part).unwrap();
+        }
+        file
+    }
+
+    #[test]
+    fn tile_ids_follow_the_hilbert_curve() {
+        assert_eq!(tile_id(0, 0, 0), 0);
+        assert_eq!(tile_id(0, 0, 1), 1);
+        assert_eq!(tile_id(0, 1, 1), 2);
+        assert_eq!(tile_id(1, 1, 1), 3);
+        assert_eq!(tile_id(1, 0, 1), 4);
+        assert_eq!(tile_id(0, 0, 2), 5);
+        assert_eq!(tile_id(3423, 1763, 12), 19078479);
+    }
+
+    #[test]
+    fn read_varints() {
+        let bytes = [0x00, 0x7f, 0xac, 0x02];
+        let mut position = 0;
+        assert_eq!(read_varint(&bytes, &mut position).unwrap(), 0);
+        assert_eq!(read_varint(&bytes, &mut position).unwrap(), 127);
+        assert_eq!(read_varint(&bytes, &mut position).unwrap(), 300);
+        assert!(read_varint(&bytes, &mut position).is_err());
+        assert!(read_varint(&[0xff; 10], &mut 0).is_err());
+    }
+
+    #[test]
+    fn directory_entries() {
+        let entries =
+            parse_directory(&directory(&[(0, 1, 3, 1), (1, 0, 3, 2), (5, 10, 4, 0)])).unwrap();
+        let entries: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.tile_id, entry.offset, entry.length, entry.run_length))
+            .collect();
+        assert_eq!(entries, vec![(0, 0, 3, 1), (1, 3, 3, 2), (5, 9, 4, 0)]);
+    }
+
+    #[test]
+    fn header() {
+        let file = archive();
+        let bytes = std::fs::read(file.path()).unwrap();
+        let header = Header::parse(&bytes[..HEADER_LENGTH]).unwrap();
+        assert_eq!(header.root_directory_offset, HEADER_LENGTH as u64);
+        assert_eq!(header.internal_compression, Compression::None);
+        assert_eq!(header.file_extension(), Some("png"));
+        assert_eq!(header.max_zoom, 2);
+
+        let mut wrong_version = bytes[..HEADER_LENGTH].to_vec();
+        wrong_version[7] = 2;
+        assert!(Header::parse(&wrong_version).is_err());
+        assert!(Header::parse(b"MBTiles").is_err());
+    }
+
+    #[test]
+    fn read_tiles() {
+        let file = archive();
+        let source = PmTilesSource::open(file.path().to_str().unwrap()).unwrap();
+        let tile = |x, y, z| source.read_tile(x, y, z).unwrap();
+        assert_eq!(tile(0, 0, 0), Some(b"abc".to_vec()));
+        // A run of two tiles
+        assert_eq!(tile(0, 0, 1), Some(b"def".to_vec()));
+        assert_eq!(tile(0, 1, 1), Some(b"def".to_vec()));
+        assert_eq!(tile(1, 1, 1), None);
+        // Through the leaf directory
+        assert_eq!(tile(0, 0, 2), Some(b"ghi".to_vec()));
+        assert_eq!(tile(1, 0, 2), None);
+    }
+}
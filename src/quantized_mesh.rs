@@ -0,0 +1,338 @@
+use super::map_services::TileError;
+use flate2::read::GzDecoder;
+use std::convert::TryInto;
+use std::io::Read;
+
+// Cesium's quantized-mesh-1.0 terrain tiles, triangulated irregular networks
+// with vertices quantized within the tile.
+// From: https://github.com/CesiumGS/quantized-mesh
+
+const HEADER_LENGTH: usize = 88;
+const MAX_QUANTIZED: f32 = 32767.;
+const OCT_VERTEX_NORMALS_EXTENSION: u8 = 1;
+
+pub struct QuantizedMesh {
+    /// Position of each vertex within the tile, from 0 to 1, `v` going north.
+    pub uv: Vec<[f32; 2]>,
+    /// Height of each vertex in meters.
+    pub heights: Vec<f32>,
+    pub indices: Vec<u32>,
+    /// Unit normals in Earth-centered, Earth-fixed coordinates, when the tile
+    /// has the oct-encoded vertex normals extension.
+    pub normals: Option<Vec<[f32; 3]>>,
+}
+
+fn decode_error(message: &str) -> TileError {
+    TileError::Decode(format!("invalid quantized-mesh tile: {}", message))
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], TileError> {
+        let bytes = self
+            .data
+            .get(self.offset..self.offset + length)
+            .ok_or_else(|| decode_error("truncated"))?;
+        self.offset += length;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, TileError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, TileError> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, TileError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn f32(&mut self) -> Result<f32, TileError> {
+        Ok(f32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn align(&mut self, alignment: usize) {
+        self.offset = self.offset.div_ceil(alignment) * alignment;
+    }
+
+    /// Indices are 32 bits when there are more vertices than 16 bits can
+    /// address.
+    fn indices(&mut self, count: usize, wide: bool) -> Result<Vec<u32>, TileError> {
+        if wide {
+            (0..count).map(|_| self.u32()).collect()
+        } else {
+            (0..count)
+                .map(|_| self.u16().map(|index| index as u32))
+                .collect()
+        }
+    }
+}
+
+fn zig_zag_decode(value: u16) -> i32 {
+    (value >> 1) as i32 ^ -((value & 1) as i32)
+}
+
+/// Deltas from the previous value, zig-zag encoded.
+fn decode_deltas(values: &[u16]) -> Vec<f32> {
+    let mut value = 0;
+    values
+        .iter()
+        .map(|delta| {
+            value += zig_zag_decode(*delta);
+            value as f32
+        })
+        .collect()
+}
+
+/// Each index is given as its distance below the highest index so far plus
+/// one, the "high water mark".
+fn decode_high_water_mark(indices: &mut [u32]) -> Result<(), TileError> {
+    let mut highest = 0_u32;
+    for index in indices.iter_mut() {
+        let code = *index;
+        *index = highest
+            .checked_sub(code)
+            .ok_or_else(|| decode_error("invalid index"))?;
+        if code == 0 {
+            highest += 1;
+        }
+    }
+    Ok(())
+}
+
+fn oct_decode(x: u8, y: u8) -> [f32; 3] {
+    let sign_not_zero = |value: f32| if value < 0. { -1. } else { 1. };
+    let mut x = x as f32 / 255. * 2. - 1.;
+    let mut y = y as f32 / 255. * 2. - 1.;
+    let z = 1. - x.abs() - y.abs();
+    if z < 0. {
+        let old_x = x;
+        x = (1. - y.abs()) * sign_not_zero(old_x);
+        y = (1. - old_x.abs()) * sign_not_zero(y);
+    }
+    let length = (x * x + y * y + z * z).sqrt();
+    [x / length, y / length, z / length]
+}
+
+/// Decodes a quantized-mesh tile, which servers often store gzipped.
+pub fn decode_quantized_mesh(data: &[u8]) -> Result<QuantizedMesh, TileError> {
+    let mut unzipped = Vec::new();
+    let data = if data.starts_with(&[0x1f, 0x8b]) {
+        GzDecoder::new(data).read_to_end(&mut unzipped)?;
+        &unzipped
+    } else {
+        data
+    };
+
+    let mut reader = Reader { data, offset: 0 };
+    // The center, bounding sphere and horizon occlusion point are only used
+    // for culling on a globe
+    reader.bytes(24)?;
+    let min_height = reader.f32()?;
+    let max_height = reader.f32()?;
+    reader.offset = HEADER_LENGTH;
+
+    let vertex_count = reader.u32()? as usize;
+    let buffer = |reader: &mut Reader| -> Result<Vec<f32>, TileError> {
+        let values: Vec<u16> = (0..vertex_count)
+            .map(|_| reader.u16())
+            .collect::<Result<_, _>>()?;
+        Ok(decode_deltas(&values))
+    };
+    let u = buffer(&mut reader)?;
+    let v = buffer(&mut reader)?;
+    let heights = buffer(&mut reader)?;
+    let uv = u
+        .iter()
+        .zip(&v)
+        .map(|(u, v)| [u / MAX_QUANTIZED, v / MAX_QUANTIZED])
+        .collect();
+    let heights = heights
+        .iter()
+        .map(|height| min_height + height / MAX_QUANTIZED * (max_height - min_height))
+        .collect();
+
+    // Padded so that the indices are aligned on their size
+    let wide = vertex_count > 65536;
+    reader.align(if wide { 4 } else { 2 });
+    let triangle_count = reader.u32()? as usize;
+    let mut indices = reader.indices(triangle_count * 3, wide)?;
+    decode_high_water_mark(&mut indices)?;
+    if indices.iter().any(|index| *index as usize >= vertex_count) {
+        return Err(decode_error("index out of range"));
+    }
+    // The vertices on the west, south, east and north edges are only
+    // checked: the skirts follow the outline of the triangles instead, as
+    // an ancestor's triangles covering a tile aren't cut to its edges
+    for _ in 0..4 {
+        let count = reader.u32()? as usize;
+        let edge_indices = reader.indices(count, wide)?;
+        if edge_indices
+            .iter()
+            .any(|index| *index as usize >= vertex_count)
+        {
+            return Err(decode_error("edge index out of range"));
+        }
+    }
+
+    let mut normals = None;
+    while reader.offset < data.len() {
+        let extension_id = reader.u8()?;
+        let length = reader.u32()? as usize;
+        let extension = reader.bytes(length)?;
+        if extension_id == OCT_VERTEX_NORMALS_EXTENSION && length == vertex_count * 2 {
+            normals = Some(
+                extension
+                    .chunks_exact(2)
+                    .map(|normal| oct_decode(normal[0], normal[1]))
+                    .collect(),
+            );
+        }
+    }
+
+    Ok(QuantizedMesh {
+        uv,
+        heights,
+        indices,
+        normals,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    /// Encodes a tile with heights from 100 to 200 meters and a vertex on
+    /// each edge.
+    fn encode_tile(vertices: &[[u16; 3]], indices: &[u32], normals: Option<&[[u8; 2]]>) -> Vec<u8> {
+        let mut data = vec![0; HEADER_LENGTH];
+        data[24..28].copy_from_slice(&100_f32.to_le_bytes());
+        data[28..32].copy_from_slice(&200_f32.to_le_bytes());
+        data.extend_from_slice(&(vertices.len() as u32).to_le_bytes());
+        for i in 0..3 {
+            let mut previous = 0;
+            for vertex in vertices {
+                let delta = vertex[i] as i32 - previous;
+                previous = vertex[i] as i32;
+                data.extend_from_slice(&(((delta << 1) ^ (delta >> 31)) as u16).to_le_bytes());
+            }
+        }
+        let wide = vertices.len() > 65536;
+        let push_index = |data: &mut Vec<u8>, index: u32| {
+            if wide {
+                data.extend_from_slice(&index.to_le_bytes());
+            } else {
+                data.extend_from_slice(&(index as u16).to_le_bytes());
+            }
+        };
+        let alignment = if wide { 4 } else { 2 };
+        data.resize(data.len().div_ceil(alignment) * alignment, 0);
+        data.extend_from_slice(&(indices.len() as u32 / 3).to_le_bytes());
+        let mut highest = 0;
+        for index in indices {
+            push_index(&mut data, highest - index);
+            if *index == highest {
+                highest += 1;
+            }
+        }
+        for edge in 0..4 {
+            data.extend_from_slice(&1_u32.to_le_bytes());
+            push_index(&mut data, edge % vertices.len() as u32);
+        }
+        if let Some(normals) = normals {
+            data.push(OCT_VERTEX_NORMALS_EXTENSION);
+            data.extend_from_slice(&(normals.len() as u32 * 2).to_le_bytes());
+            for normal in normals {
+                data.extend_from_slice(normal);
+            }
+        }
+        data
+    }
+
+    const SQUARE: [[u16; 3]; 4] = [
+        [0, 0, 0],
+        [32767, 0, 16383],
+        [32767, 32767, 32767],
+        [0, 32767, 0],
+    ];
+    const SQUARE_INDICES: [u32; 6] = [0, 1, 2, 0, 2, 3];
+
+    #[test]
+    fn zig_zag_deltas() {
+        assert_eq!(zig_zag_decode(0), 0);
+        assert_eq!(zig_zag_decode(1), -1);
+        assert_eq!(zig_zag_decode(2), 1);
+        assert_eq!(zig_zag_decode(65535), -32768);
+        assert_eq!(decode_deltas(&[4, 1, 3, 10]), vec![2., 1., -1., 4.]);
+    }
+
+    #[test]
+    fn high_water_mark() {
+        let mut indices = [0, 0, 0, 2, 1, 0];
+        decode_high_water_mark(&mut indices).unwrap();
+        assert_eq!(indices, [0, 1, 2, 1, 2, 3]);
+        assert!(decode_high_water_mark(&mut [0, 2]).is_err());
+    }
+
+    #[test]
+    fn oct_normals() {
+        let close = |a: [f32; 3], b: [f32; 3]| (0..3).all(|i| (a[i] - b[i]).abs() < 0.01);
+        assert!(close(oct_decode(128, 128), [0., 0., 1.]));
+        assert!(close(oct_decode(255, 128), [1., 0., 0.]));
+        assert!(close(oct_decode(128, 0), [0., -1., 0.]));
+        assert!(close(oct_decode(255, 255), [0., 0., -1.]));
+    }
+
+    #[test]
+    fn decodes_a_tile() {
+        let normals = [[128, 128], [255, 128], [128, 255], [0, 128]];
+        let data = encode_tile(&SQUARE, &SQUARE_INDICES, Some(&normals));
+        let mesh = decode_quantized_mesh(&data).unwrap();
+        assert_eq!(mesh.uv, vec![[0., 0.], [1., 0.], [1., 1.], [0., 1.]]);
+        assert_eq!(mesh.heights[0], 100.);
+        assert!((mesh.heights[1] - 150.).abs() < 0.01);
+        assert_eq!(mesh.heights[2], 200.);
+        assert_eq!(mesh.indices, SQUARE_INDICES);
+        let normals = mesh.normals.unwrap();
+        assert!((normals[0][2] - 1.).abs() < 0.01);
+        assert!((normals[1][0] - 1.).abs() < 0.01);
+
+        let without_normals = encode_tile(&SQUARE, &SQUARE_INDICES, None);
+        assert!(decode_quantized_mesh(&without_normals)
+            .unwrap()
+            .normals
+            .is_none());
+        assert!(decode_quantized_mesh(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn gzipped_tile() {
+        let data = encode_tile(&SQUARE, &SQUARE_INDICES, None);
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        let mesh = decode_quantized_mesh(&encoder.finish().unwrap()).unwrap();
+        assert_eq!(mesh.uv.len(), 4);
+        assert_eq!(mesh.indices, SQUARE_INDICES);
+    }
+
+    #[test]
+    fn wide_indices() {
+        // More vertices than 16 bits can address, the indices after them
+        // padded to 4 bytes
+        let mut vertices = vec![[0, 0, 0]; 65536];
+        vertices.push([32767, 32767, 32767]);
+        let indices = [0, 1, 2, 2, 1, 3];
+        let data = encode_tile(&vertices, &indices, None);
+        let mesh = decode_quantized_mesh(&data).unwrap();
+        assert_eq!(mesh.heights[65536], 200.);
+        assert_eq!(mesh.indices, indices);
+    }
+}
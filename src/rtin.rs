@@ -0,0 +1,228 @@
+// Right-triangulated irregular networks: the grid is split in two right
+// triangles, and each triangle in two from its right angle to the middle of
+// its hypotenuse, for as long as the heights under it are too far from it.
+// From: https://github.com/mapbox/martini, after Evans et al.,
+// "Right-triangulated irregular networks" (2001)
+
+/// The corners at either end of the hypotenuse of every triangle of the
+/// hierarchy, `[ax, ay, bx, by]`, parents before their children.
+fn triangle_coords(tile_size: usize) -> Vec<[usize; 4]> {
+    let triangle_count = tile_size * tile_size * 2 - 2;
+    (0..triangle_count)
+        .map(|i| {
+            // The bits of the id, past the leading one, are the path from one
+            // of the two root triangles down to the triangle
+            let mut id = i + 2;
+            let (mut ax, mut ay, mut bx, mut by, mut cx, mut cy) = (0, 0, 0, 0, 0, 0);
+            if id & 1 == 1 {
+                bx = tile_size;
+                by = tile_size;
+                cx = tile_size;
+            } else {
+                ax = tile_size;
+                ay = tile_size;
+                cy = tile_size;
+            }
+            id >>= 1;
+            while id > 1 {
+                let (mx, my) = ((ax + bx) / 2, (ay + by) / 2);
+                if id & 1 == 1 {
+                    bx = ax;
+                    by = ay;
+                    ax = cx;
+                    ay = cy;
+                } else {
+                    ax = bx;
+                    ay = by;
+                    bx = cx;
+                    by = cy;
+                }
+                cx = mx;
+                cy = my;
+                id >>= 1;
+            }
+            [ax, ay, bx, by]
+        })
+        .collect()
+}
+
+/// The error of splitting each triangle at the middle of its hypotenuse, by
+/// that middle point: the most the heights under the unsplit triangle, and
+/// under its descendants, are off. With `full_edges`, the points on the
+/// grid's edges have an infinite error so that they're all kept, as do the
+/// points without data and those around them with `valid`.
+fn split_errors(
+    heights: &[f32],
+    size: usize,
+    full_edges: bool,
+    valid: Option<&[bool]>,
+) -> Vec<f32> {
+    let tile_size = size - 1;
+    let mut near_nodata = vec![false; size * size];
+    if let Some(valid) = valid {
+        for i in (0..size * size).filter(|i| !valid[*i]) {
+            let (x, y) = (i % size, i / size);
+            for ny in y.saturating_sub(1)..=(y + 1).min(tile_size) {
+                for nx in x.saturating_sub(1)..=(x + 1).min(tile_size) {
+                    near_nodata[ny * size + nx] = true;
+                }
+            }
+        }
+    }
+    let coords = triangle_coords(tile_size);
+    // A 2 point grid has no triangles to split
+    let parent_count = coords.len().saturating_sub(tile_size * tile_size);
+    let mut errors = vec![0_f32; size * size];
+    // Children first, so that their errors add up into their parents'
+    for (i, &[ax, ay, bx, by]) in coords.iter().enumerate().rev() {
+        let (mx, my) = ((ax + bx) / 2, (ay + by) / 2);
+        let (cx, cy) = (mx + my - ay, my + ax - mx);
+        let middle = my * size + mx;
+        let interpolated = (heights[ay * size + ax] + heights[by * size + bx]) / 2.;
+        let mut error = errors[middle].max((interpolated - heights[middle]).abs());
+        let on_edge = mx == 0 || my == 0 || mx == tile_size || my == tile_size;
+        if (full_edges && on_edge) || near_nodata[middle] {
+            error = f32::INFINITY;
+        }
+        if i < parent_count {
+            let left_child = ((ay + cy) / 2) * size + (ax + cx) / 2;
+            let right_child = ((by + cy) / 2) * size + (bx + cx) / 2;
+            error = error.max(errors[left_child]).max(errors[right_child]);
+        }
+        errors[middle] = error;
+    }
+    errors
+}
+
+/// The triangles of a square grid of heights, `size` a power of 2 plus 1 a
+/// side, split only where the heights are more than `max_error` away from
+/// them. The indices are those of the grid's points, row by row, and the
+/// triangles are counterclockwise seen from above as in the full grid. With
+/// `full_edges`, the triangles along the grid's edges are split down to its
+/// points, so that the edges meet those of the neighbouring grids. With
+/// `valid`, the triangles around the points without data are split down to
+/// the grid's cells, so that none of those between points with data covers
+/// them.
+pub fn rtin_indices(
+    heights: &[f32],
+    size: usize,
+    max_error: f32,
+    full_edges: bool,
+    valid: Option<&[bool]>,
+) -> Vec<u32> {
+    debug_assert!((size - 1).is_power_of_two() && heights.len() == size * size);
+    let errors = split_errors(heights, size, full_edges, valid);
+    let mut indices = Vec::new();
+    let max = size - 1;
+    let mut triangles = vec![[0, 0, max, max, max, 0], [max, max, 0, 0, 0, max]];
+    while let Some([ax, ay, bx, by, cx, cy]) = triangles.pop() {
+        let (mx, my) = ((ax + bx) / 2, (ay + by) / 2);
+        let is_smallest = (ax as i64 - cx as i64).abs() + (ay as i64 - cy as i64).abs() <= 1;
+        if !is_smallest && errors[my * size + mx] > max_error {
+            triangles.push([cx, cy, ax, ay, mx, my]);
+            triangles.push([bx, by, cx, cy, mx, my]);
+        } else {
+            indices.extend_from_slice(&[
+                (ay * size + ax) as u32,
+                (by * size + bx) as u32,
+                (cy * size + cx) as u32,
+            ]);
+        }
+    }
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    /// Heights curving in every direction, so that no point lies on the line
+    /// between two others.
+    fn bowl(size: usize) -> Vec<f32> {
+        (0..size * size)
+            .map(|i| {
+                let (x, y) = ((i % size) as f32, (i / size) as f32);
+                x * x + 2. * y * y
+            })
+            .collect()
+    }
+
+    fn used_points(indices: &[u32]) -> HashSet<u32> {
+        indices.iter().copied().collect()
+    }
+
+    #[test]
+    fn triangle_coords_match_martini() {
+        let coords = triangle_coords(4);
+        assert_eq!(coords.len(), 30);
+        assert_eq!(
+            coords[..4],
+            [[4, 4, 0, 0], [0, 0, 4, 4], [0, 0, 0, 4], [4, 4, 4, 0]]
+        );
+        // The smallest triangles split in the hierarchy span two cells, the
+        // middle of their children's hypotenuses isn't a point of the grid
+        for [ax, ay, bx, by] in &coords[14..] {
+            let span = (ax.max(bx) - ax.min(bx), ay.max(by) - ay.min(by));
+            assert!(span == (2, 0) || span == (0, 2));
+        }
+    }
+
+    #[test]
+    fn flat_grid_is_two_triangles() {
+        let indices = rtin_indices(&[10.; 25], 5, 0., false, None);
+        assert_eq!(indices, [24, 0, 20, 0, 24, 4]);
+    }
+
+    #[test]
+    fn no_error_is_the_full_grid() {
+        let indices = rtin_indices(&bowl(5), 5, 0., false, None);
+        assert_eq!(indices.len(), 32 * 3);
+        assert_eq!(used_points(&indices).len(), 25);
+    }
+
+    #[test]
+    fn full_edges_keep_the_edge_points() {
+        let indices = rtin_indices(&[10.; 25], 5, 0., true, None);
+        let used = used_points(&indices);
+        for i in 0..5 {
+            for point in &[i, 20 + i, i * 5, i * 5 + 4] {
+                assert!(used.contains(point));
+            }
+        }
+        // Not all the cells are split in two triangles
+        assert!(indices.len() < 32 * 3);
+    }
+
+    #[test]
+    fn no_triangle_covers_a_hole() {
+        // Points (3, 3) to (5, 5) of a 9 point grid have no data
+        let size = 9;
+        let valid: Vec<bool> = (0..size * size)
+            .map(|i| !(3..=5).contains(&(i % size)) || !(3..=5).contains(&(i / size)))
+            .collect();
+        let indices = rtin_indices(&vec![10.; size * size], size, 100., false, Some(&valid));
+        let point = |i: u32| ((i as usize % size) as i64, (i as usize / size) as i64);
+        let side = |a: (i64, i64), b: (i64, i64), p: (i64, i64)| {
+            (b.0 - a.0) * (p.1 - a.1) - (b.1 - a.1) * (p.0 - a.0)
+        };
+        for triangle in indices.chunks_exact(3) {
+            if !triangle.iter().all(|i| valid[*i as usize]) {
+                continue;
+            }
+            let (a, b, c) = (point(triangle[0]), point(triangle[1]), point(triangle[2]));
+            for hole in (0..size * size).filter(|i| !valid[*i]) {
+                let p = point(hole as u32);
+                let sides = [side(a, b, p), side(b, c, p), side(c, a, p)];
+                let inside =
+                    sides.iter().all(|side| *side >= 0) || sides.iter().all(|side| *side <= 0);
+                assert!(!inside, "{:?} covers {:?}", triangle, p);
+            }
+        }
+    }
+
+    #[test]
+    fn two_point_grid() {
+        assert_eq!(rtin_indices(&bowl(2), 2, 0., false, None).len(), 2 * 3);
+    }
+}
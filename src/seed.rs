@@ -0,0 +1,414 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+mod coord_utils;
+use coord_utils::*;
+mod downloader;
+mod heightmap;
+mod map_services;
+use map_services::*;
+mod mbtiles;
+mod pmtiles;
+mod tile_cache;
+mod tile_requests;
+
+#[cfg(test)]
+mod mock_tile_server;
+
+/// Size assumed for a layer's tiles in estimates when none of them are cached.
+const DEFAULT_TILE_BYTES: u64 = 30 * 1024;
+/// Tiles being fetched at once, the downloader still limits each host.
+const CONCURRENT_TILES: usize = 32;
+
+/// A polygon as a ring of (lon, lat) points.
+type Polygon = Vec<(f64, f64)>;
+
+struct SeedOptions {
+    region: TileRegion,
+    /// Only the tiles touching one of them are seeded, when given.
+    polygons: Vec<Polygon>,
+    layers: Vec<String>,
+    dry_run: bool,
+}
+
+fn usage() -> ! {
+    panic!(
+        "Usage: seed (--bbox <west,south,east,north> | --geojson <file>) --zoom <min>-<max> \
+         [--layers imagery,elevation] [--dry-run] [tile source arguments]"
+    )
+}
+
+/// Splits the seeding arguments from the ones for `TileSources`.
+fn parse_args() -> (SeedOptions, Vec<String>) {
+    let mut bbox = None;
+    let mut polygons = Vec::new();
+    let mut zoom_range = None;
+    let mut layers = vec!["imagery".to_string(), "elevation".to_string()];
+    let mut dry_run = false;
+    let mut source_args = Vec::new();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .unwrap_or_else(|| panic!("Missing value for {}", arg))
+        };
+        match arg.as_str() {
+            "--bbox" => {
+                let value = value();
+                let coords: Vec<f64> = value.split(',').map(|c| parse_arg(&arg, c)).collect();
+                if coords.len() != 4 {
+                    usage();
+                }
+                bbox = Some((coords[0], coords[1], coords[2], coords[3]));
+            }
+            "--geojson" => polygons.extend(read_geojson_polygons(&value())),
+            "--zoom" => {
+                let value = value();
+                let (min_zoom, max_zoom) = match value.split_once('-') {
+                    Some((min_zoom, max_zoom)) => (min_zoom, max_zoom),
+                    None => (value.as_str(), value.as_str()),
+                };
+                zoom_range = Some((parse_arg(&arg, min_zoom), parse_arg(&arg, max_zoom)));
+            }
+            "--layers" => layers = value().split(',').map(|layer| layer.to_string()).collect(),
+            "--dry-run" => dry_run = true,
+            _ => source_args.push(arg),
+        }
+    }
+
+    let (min_zoom, max_zoom) = zoom_range.unwrap_or_else(|| usage());
+    let (west, south, east, north) = match bbox {
+        Some(bbox) => bbox,
+        None if !polygons.is_empty() => polygons_bounds(&polygons),
+        None => usage(),
+    };
+    let options = SeedOptions {
+        region: TileRegion {
+            west,
+            south,
+            east,
+            north,
+            min_zoom,
+            max_zoom,
+        },
+        polygons,
+        layers,
+        dry_run,
+    };
+    (options, source_args)
+}
+
+/// Reads the outer rings of the polygons in a GeoJSON file, holes are ignored.
+fn read_geojson_polygons(filename: &str) -> Vec<Polygon> {
+    let json = std::fs::read(filename)
+        .unwrap_or_else(|error| panic!("Failed to read {}: {}", filename, error));
+    let geojson: serde_json::Value = serde_json::from_slice(&json)
+        .unwrap_or_else(|error| panic!("Invalid GeoJSON in {}: {}", filename, error));
+    let mut polygons = Vec::new();
+    collect_polygons(&geojson, &mut polygons);
+    if polygons.is_empty() {
+        panic!("No polygons in {}", filename);
+    }
+    polygons
+}
+
+fn collect_polygons(geojson: &serde_json::Value, polygons: &mut Vec<Polygon>) {
+    let ring = |ring: &serde_json::Value| -> Polygon {
+        ring.as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|point| Some((point[0].as_f64()?, point[1].as_f64()?)))
+            .collect()
+    };
+    match geojson["type"].as_str() {
+        Some("FeatureCollection") => {
+            for feature in geojson["features"].as_array().into_iter().flatten() {
+                collect_polygons(feature, polygons);
+            }
+        }
+        Some("Feature") => collect_polygons(&geojson["geometry"], polygons),
+        Some("Polygon") => polygons.push(ring(&geojson["coordinates"][0])),
+        Some("MultiPolygon") => {
+            for polygon in geojson["coordinates"].as_array().into_iter().flatten() {
+                polygons.push(ring(&polygon[0]));
+            }
+        }
+        _ => {}
+    }
+}
+
+fn polygons_bounds(polygons: &[Polygon]) -> (f64, f64, f64, f64) {
+    let points = polygons.iter().flatten();
+    let west = points.clone().map(|p| p.0).fold(f64::INFINITY, f64::min);
+    let south = points.clone().map(|p| p.1).fold(f64::INFINITY, f64::min);
+    let east = points
+        .clone()
+        .map(|p| p.0)
+        .fold(f64::NEG_INFINITY, f64::max);
+    let north = points.map(|p| p.1).fold(f64::NEG_INFINITY, f64::max);
+    (west, south, east, north)
+}
+
+/// Ray casting, counting the edges crossed on the way east of the point.
+fn polygon_contains(polygon: &[(f64, f64)], lon: f64, lat: f64) -> bool {
+    let mut inside = false;
+    for (i, &(lon_1, lat_1)) in polygon.iter().enumerate() {
+        let (lon_2, lat_2) = polygon[(i + 1) % polygon.len()];
+        if (lat_1 > lat) != (lat_2 > lat)
+            && lon < lon_1 + (lat - lat_1) / (lat_2 - lat_1) * (lon_2 - lon_1)
+        {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+/// Whether two segments meet, their ends included.
+fn segments_cross(a: (f64, f64), b: (f64, f64), c: (f64, f64), d: (f64, f64)) -> bool {
+    // Which side of the line through p and q the point r is on
+    let side = |p: (f64, f64), q: (f64, f64), r: (f64, f64)| {
+        (q.0 - p.0) * (r.1 - p.1) - (q.1 - p.1) * (r.0 - p.0)
+    };
+    let within = |p: (f64, f64), q: (f64, f64), r: (f64, f64)| {
+        r.0 >= p.0.min(q.0) && r.0 <= p.0.max(q.0) && r.1 >= p.1.min(q.1) && r.1 <= p.1.max(q.1)
+    };
+    let (side_c, side_d) = (side(a, b, c), side(a, b, d));
+    let (side_a, side_b) = (side(c, d, a), side(c, d, b));
+    if side_c * side_d < 0. && side_a * side_b < 0. {
+        return true;
+    }
+    // Or an end of one is on the other
+    (side_c == 0. && within(a, b, c))
+        || (side_d == 0. && within(a, b, d))
+        || (side_a == 0. && within(c, d, a))
+        || (side_b == 0. && within(c, d, b))
+}
+
+/// Whether a tile and a polygon overlap: a corner of the tile is in the
+/// polygon, a point of the polygon is in the tile, or their edges cross, as
+/// they do for a thin polygon along a road or a river.
+fn tile_touches_polygon(x: u32, y: u32, z: u32, polygon: &[(f64, f64)]) -> bool {
+    let (north, west) = num2deg(x, y, z);
+    let (south, east) = num2deg(x + 1, y + 1, z);
+    let corners = [(west, north), (east, north), (east, south), (west, south)];
+    corners
+        .iter()
+        .any(|&(lon, lat)| polygon_contains(polygon, lon, lat))
+        || polygon
+            .iter()
+            .any(|&(lon, lat)| lon >= west && lon <= east && lat >= south && lat <= north)
+        || (0..polygon.len()).any(|i| {
+            let (a, b) = (polygon[i], polygon[(i + 1) % polygon.len()]);
+            (0..4).any(|j| segments_cross(a, b, corners[j], corners[(j + 1) % 4]))
+        })
+}
+
+impl SeedOptions {
+    /// Lowest zoom levels first, so that an interrupted seed still leaves
+    /// ancestors for the offline fallback.
+    fn tiles(&self) -> Vec<(u32, u32, u32)> {
+        let mut tiles = Vec::new();
+        for z in self.region.min_zoom..=self.region.max_zoom {
+            let ((min_x, min_y), (max_x, max_y)) = self.region.tile_range(z);
+            for y in min_y..=max_y {
+                for x in min_x..=max_x {
+                    if self.polygons.is_empty()
+                        || self
+                            .polygons
+                            .iter()
+                            .any(|polygon| tile_touches_polygon(x, y, z, polygon))
+                    {
+                        tiles.push((x, y, z));
+                    }
+                }
+            }
+        }
+        tiles
+    }
+}
+
+/// Prints the number of tiles per zoom level and their expected size, going
+/// by the average size of the layers' tiles already in the cache.
+fn estimate(sources: &TileSources, layers: &[Arc<dyn TileSource>], tiles: &[(u32, u32, u32)]) {
+    let cache_usage = sources.cache.usage().unwrap_or_default();
+    let mut total_bytes = 0;
+    for layer in layers {
+        let (cached_tiles, cached_bytes) = cache_usage
+            .iter()
+            .filter(|usage| usage.layer == layer.name())
+            .fold((0, 0), |(tiles, bytes), usage| {
+                (tiles + usage.tiles, bytes + usage.bytes)
+            });
+        let tile_bytes = if cached_tiles > 0 {
+            cached_bytes / cached_tiles
+        } else {
+            DEFAULT_TILE_BYTES
+        };
+        println!("{}:", layer.name());
+        let mut z = None;
+        for zoom in tiles.iter().map(|tile| tile.2) {
+            if z == Some(zoom) || zoom > layer.max_zoom() {
+                continue;
+            }
+            z = Some(zoom);
+            let count = tiles.iter().filter(|tile| tile.2 == zoom).count() as u64;
+            println!(
+                "  z{}: {} tiles, ~{:.1} MB",
+                zoom,
+                count,
+                (count * tile_bytes) as f64 / (1024. * 1024.)
+            );
+            total_bytes += count * tile_bytes;
+        }
+    }
+    println!("Total: ~{:.1} MB", total_bytes as f64 / (1024. * 1024.));
+}
+
+/// Fetches every tile through the cache. Cached tiles that are still fresh
+/// aren't downloaded again, so an interrupted seed resumes where it stopped
+/// when run again.
+async fn seed(sources: &TileSources, layers: &[Arc<dyn TileSource>], tiles: &[(u32, u32, u32)]) {
+    let total = (layers.len() * tiles.len()) as u64;
+    let done = Arc::new(AtomicU64::new(0));
+    let missing = Arc::new(AtomicU64::new(0));
+    let failed = Arc::new(AtomicU64::new(0));
+    let permits = Arc::new(Semaphore::new(CONCURRENT_TILES));
+
+    // The tasks aren't kept, each one holds a permit until it's done, so all
+    // of them are done once every permit is back
+    for layer in layers {
+        for &(x, y, z) in tiles {
+            let permit = permits.clone().acquire_owned().await.unwrap();
+            let source = layer.clone();
+            let sources = sources.clone();
+            let done = done.clone();
+            let missing = missing.clone();
+            let failed = failed.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                let result =
+                    get_tile(&*source, &*sources.cache, &sources.downloader, x, y, z).await;
+                match result {
+                    Ok(_) => {}
+                    Err(TileError::NotFound) => {
+                        missing.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(error) => {
+                        println!(
+                            "Failed to seed {} ({}, {}, {}): {}",
+                            source.name(),
+                            x,
+                            y,
+                            z,
+                            error
+                        );
+                        failed.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+                if done % 100 == 0 || done == total {
+                    println!(
+                        "{}/{} tiles ({:.0}%)",
+                        done,
+                        total,
+                        done as f64 * 100. / total as f64
+                    );
+                }
+            });
+        }
+    }
+    let _ = permits.acquire_many(CONCURRENT_TILES as u32).await;
+    println!(
+        "Seeded {} tiles, {} not available from the source, {} failed",
+        total - missing.load(Ordering::Relaxed) - failed.load(Ordering::Relaxed),
+        missing.load(Ordering::Relaxed),
+        failed.load(Ordering::Relaxed)
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    let (options, source_args) = parse_args();
+    let sources = TileSources::from_arg_list(source_args);
+    let layers: Vec<Arc<dyn TileSource>> = options
+        .layers
+        .iter()
+        .map(|layer| match layer.as_str() {
+            "imagery" => sources.imagery.clone(),
+            "elevation" => sources.elevation.clone(),
+            _ => panic!("Unknown layer {}, expected imagery or elevation", layer),
+        })
+        .collect();
+    let tiles = options.tiles();
+
+    if options.dry_run {
+        estimate(&sources, &layers, &tiles);
+    } else {
+        seed(&sources, &layers, &tiles).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The bounds of a tile, west, south, east and north.
+    fn tile_bounds(x: u32, y: u32, z: u32) -> (f64, f64, f64, f64) {
+        let (north, west) = num2deg(x, y, z);
+        let (south, east) = num2deg(x + 1, y + 1, z);
+        (west, south, east, north)
+    }
+
+    #[test]
+    fn segments() {
+        assert!(segments_cross((0., 0.), (2., 2.), (0., 2.), (2., 0.)));
+        assert!(segments_cross((0., 0.), (2., 0.), (1., 0.), (1., 1.)));
+        assert!(segments_cross((0., 0.), (2., 0.), (1., 0.), (3., 0.)));
+        assert!(!segments_cross((0., 0.), (2., 0.), (3., 0.), (4., 0.)));
+        assert!(!segments_cross((0., 0.), (1., 1.), (0., 1.), (0.4, 0.6)));
+        assert!(!segments_cross((0., 0.), (1., 0.), (2., 0.), (0.5, 1.)));
+    }
+
+    #[test]
+    fn polygon_crossing_a_tile() {
+        let (x, y, z) = (163, 395, 10);
+        let (west, south, east, north) = tile_bounds(x, y, z);
+        // A corridor across the tile, missing its corners and its center,
+        // with none of its points in the tile
+        let lat = north - (north - south) / 4.;
+        let width = (north - south) / 100.;
+        let corridor = vec![
+            (west - 1., lat - width),
+            (east + 1., lat - width),
+            (east + 1., lat + width),
+            (west - 1., lat + width),
+        ];
+        assert!(tile_touches_polygon(x, y, z, &corridor));
+        // The same corridor past the tile's north edge
+        let past_north: Vec<(f64, f64)> = corridor
+            .iter()
+            .map(|(lon, lat)| (*lon, lat + (north - south) / 2.))
+            .collect();
+        assert!(!tile_touches_polygon(x, y, z, &past_north));
+    }
+
+    #[test]
+    fn polygon_containing_a_tile() {
+        let (x, y, z) = (163, 395, 10);
+        let (west, south, east, north) = tile_bounds(x, y, z);
+        let around = vec![
+            (west - 1., south - 1.),
+            (east + 1., south - 1.),
+            (east + 1., north + 1.),
+            (west - 1., north + 1.),
+        ];
+        assert!(tile_touches_polygon(x, y, z, &around));
+        // And a polygon within the tile
+        let (lon, lat) = ((west + east) / 2., (south + north) / 2.);
+        let size = (east - west) / 10.;
+        let within = vec![(lon, lat), (lon + size, lat), (lon, lat + size)];
+        assert!(tile_touches_polygon(x, y, z, &within));
+    }
+}
@@ -0,0 +1,244 @@
+use super::coord_utils::{deg2num, tile_ancestor};
+use super::terrain_mesh::HEIGHT_SCALE;
+use bevy::prelude::*;
+use std::collections::HashSet;
+
+// The terrain is a quadtree of tiles: each root tile is split into its four
+// children, and those into theirs, for as long as the tile would look too
+// coarse from the camera. Near terrain is shown at high zoom levels and far
+// terrain at low ones.
+
+/// Zoom level of the tiles the quadtree starts from.
+pub const ROOT_ZOOM: u32 = 10;
+/// Side of a root tile in the scene.
+pub const ROOT_TILE_SIZE: f32 = 256. * 0.3;
+/// How many root tiles are shown on each side of the one under the user's
+/// position.
+const ROOT_RADIUS: u32 = 1;
+/// Above the highest peaks, in meters. The tiles' heights aren't known
+/// before they're loaded, so their distance to the camera is taken from a
+/// box this high.
+const MAX_TERRAIN_HEIGHT: f32 = 9000.;
+
+pub type TileId = (u32, u32, u32);
+
+pub struct LodOptions {
+    /// The tiles aren't split past this zoom level.
+    pub max_zoom: u32,
+    /// How many pixels a tile's error may span on screen before it's split.
+    pub max_screen_error: f32,
+}
+
+pub struct LodCamera {
+    pub position: Vec3,
+    /// Vertical field of view, in radians.
+    pub fov: f32,
+    pub viewport_height: f32,
+}
+
+/// The tiles shown, each one covering part of the root tiles, none of them
+/// overlapping.
+#[derive(Default)]
+pub struct TerrainLod {
+    pub selected: HashSet<TileId>,
+}
+
+/// Where the tiles are placed in the scene: the root tile under the user's
+/// position is centered on the origin, north towards -z.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SceneOrigin {
+    pub x: u32,
+    pub y: u32,
+}
+
+impl SceneOrigin {
+    pub fn new(lat: f64, lon: f64) -> SceneOrigin {
+        let (x, y) = deg2num(lat, lon, ROOT_ZOOM);
+        SceneOrigin { x, y }
+    }
+
+    pub fn roots(&self) -> Vec<TileId> {
+        let last_tile = 2_u32.pow(ROOT_ZOOM) - 1;
+        let range = |center: u32| {
+            center.saturating_sub(ROOT_RADIUS)..=(center + ROOT_RADIUS).min(last_tile)
+        };
+        range(self.x)
+            .flat_map(|x| range(self.y).map(move |y| (x, y, ROOT_ZOOM)))
+            .collect()
+    }
+
+    /// The north-west corner of a tile in the scene, on the x and z axes,
+    /// and the length of its sides.
+    pub fn tile_rect(&self, x: u32, y: u32, z: u32) -> (Vec2, f32) {
+        let tile_size = ROOT_TILE_SIZE / 2_f32.powi(z as i32 - ROOT_ZOOM as i32);
+        let scale = 2_f64.powi(ROOT_ZOOM as i32 - z as i32);
+        let corner = Vec2::new(
+            ((x as f64 * scale - self.x as f64) as f32 - 0.5) * ROOT_TILE_SIZE,
+            ((y as f64 * scale - self.y as f64) as f32 - 0.5) * ROOT_TILE_SIZE,
+        );
+        (corner, tile_size)
+    }
+}
+
+fn children((x, y, z): TileId) -> [TileId; 4] {
+    [
+        (x * 2, y * 2, z + 1),
+        (x * 2 + 1, y * 2, z + 1),
+        (x * 2, y * 2 + 1, z + 1),
+        (x * 2 + 1, y * 2 + 1, z + 1),
+    ]
+}
+
+/// Whether `tile` is `ancestor` or lies within it.
+pub fn tile_contains(ancestor: TileId, tile: TileId) -> bool {
+    tile.2 >= ancestor.2 && tile_ancestor(tile.0, tile.1, tile.2, tile.2 - ancestor.2) == ancestor
+}
+
+pub fn tiles_overlap(a: TileId, b: TileId) -> bool {
+    tile_contains(a, b) || tile_contains(b, a)
+}
+
+/// How many pixels on screen a tile's error spans. The error of a tile's
+/// mesh is about the spacing of its heightmap's points, as Cesium estimates
+/// it for tiles of 65 points a side.
+fn screen_error(origin: &SceneOrigin, (x, y, z): TileId, camera: &LodCamera) -> f32 {
+    let (corner, tile_size) = origin.tile_rect(x, y, z);
+    let geometric_error = tile_size / 65.;
+    let max_height = MAX_TERRAIN_HEIGHT * HEIGHT_SCALE;
+    let closest = Vec3::new(
+        camera.position.x.max(corner.x).min(corner.x + tile_size),
+        camera.position.y.max(0.).min(max_height),
+        camera.position.z.max(corner.y).min(corner.y + tile_size),
+    );
+    let distance = camera.position.distance(closest).max(f32::EPSILON);
+    geometric_error * camera.viewport_height / (2. * distance * (camera.fov / 2.).tan())
+}
+
+/// Splits the root tiles until each tile's error on screen is small enough.
+pub fn select_tiles(
+    origin: &SceneOrigin,
+    camera: &LodCamera,
+    options: &LodOptions,
+) -> HashSet<TileId> {
+    let mut selected = HashSet::new();
+    let mut tiles = origin.roots();
+    while let Some(tile) = tiles.pop() {
+        if tile.2 < options.max_zoom
+            && screen_error(origin, tile, camera) > options.max_screen_error
+        {
+            tiles.extend(children(tile).iter());
+        } else {
+            selected.insert(tile);
+        }
+    }
+    selected
+}
+
+/// How many zoom levels coarser than a tile the selected tiles next to it
+/// are, west, north, east and south. 0 when they're as fine or finer, as
+/// they follow the tile's edge themselves.
+pub fn edge_levels(selected: &HashSet<TileId>, (x, y, z): TileId) -> [u32; 4] {
+    let neighbours = [
+        (x.checked_sub(1), Some(y)),
+        (Some(x), y.checked_sub(1)),
+        (Some(x + 1), Some(y)),
+        (Some(x), Some(y + 1)),
+    ];
+    let mut levels = [0; 4];
+    for (level, neighbour) in levels.iter_mut().zip(&neighbours) {
+        if let (Some(x), Some(y)) = *neighbour {
+            *level = (1..=z)
+                .find(|levels_up| selected.contains(&tile_ancestor(x, y, z, *levels_up)))
+                .unwrap_or(0);
+        }
+    }
+    levels
+}
+
+/// The selected tiles covering part of a tile: one of its ancestors, or
+/// some of its descendants.
+pub fn covering_tiles(selected: &HashSet<TileId>, tile: TileId) -> Vec<TileId> {
+    selected
+        .iter()
+        .filter(|selected| tiles_overlap(**selected, tile))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiles_within_tiles() {
+        assert!(tile_contains((1, 1, 1), (1, 1, 1)));
+        assert!(tile_contains((1, 1, 1), (2, 3, 2)));
+        assert!(tile_contains((0, 0, 0), (5, 7, 3)));
+        assert!(!tile_contains((1, 1, 1), (0, 3, 2)));
+        assert!(!tile_contains((2, 3, 2), (1, 1, 1)));
+        assert!(tiles_overlap((2, 3, 2), (1, 1, 1)));
+        assert!(!tiles_overlap((0, 0, 1), (1, 1, 1)));
+    }
+
+    #[test]
+    fn levels_of_the_neighbours() {
+        let selected: HashSet<TileId> = [(1, 1, 2), (1, 0, 1), (4, 4, 3), (0, 1, 1)]
+            .iter()
+            .cloned()
+            .collect();
+        // As fine to the west, finer to the south, coarser to the north and east
+        assert_eq!(edge_levels(&selected, (2, 1, 2)), [0, 1, 1, 0]);
+        // Nothing past the edges of the map
+        assert_eq!(edge_levels(&selected, (0, 1, 2)), [0, 0, 0, 1]);
+        assert_eq!(edge_levels(&selected, (0, 0, 0)), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn tiles_covering_a_tile() {
+        let selected: HashSet<TileId> = [(0, 0, 1), (2, 0, 2), (3, 1, 2), (1, 1, 1)]
+            .iter()
+            .cloned()
+            .collect();
+        let mut descendants = covering_tiles(&selected, (1, 0, 1));
+        descendants.sort_unstable();
+        assert_eq!(descendants, vec![(2, 0, 2), (3, 1, 2)]);
+        assert_eq!(covering_tiles(&selected, (1, 1, 2)), vec![(0, 0, 1)]);
+        assert_eq!(covering_tiles(&selected, (0, 0, 0)).len(), 4);
+    }
+
+    #[test]
+    fn splits_the_tiles_near_the_camera() {
+        let origin = SceneOrigin::new(46.5, 7.5);
+        let options = LodOptions {
+            max_zoom: 14,
+            max_screen_error: 2.,
+        };
+        let camera = |height: f32| LodCamera {
+            position: Vec3::new(0., height, 0.),
+            fov: std::f32::consts::FRAC_PI_4,
+            viewport_height: 1000.,
+        };
+
+        let far = select_tiles(&origin, &camera(1e6), &options);
+        let mut roots = origin.roots();
+        roots.sort_unstable();
+        let mut far: Vec<TileId> = far.into_iter().collect();
+        far.sort_unstable();
+        assert_eq!(far, roots);
+
+        let near = select_tiles(&origin, &camera(1.), &options);
+        // The tiles cover the root tiles without overlapping
+        let area: f64 = near
+            .iter()
+            .map(|tile| 0.25_f64.powi((tile.2 - ROOT_ZOOM) as i32))
+            .sum();
+        assert_eq!(area, roots.len() as f64);
+        for a in near.iter() {
+            assert!(near.iter().all(|b| a == b || !tiles_overlap(*a, *b)));
+        }
+        // Down to the highest zoom level under the camera
+        let under_camera = (origin.x * 16 + 8, origin.y * 16 + 8, 14);
+        assert!(near.contains(&under_camera));
+        assert!(near.iter().any(|tile| tile.2 < 14));
+    }
+}
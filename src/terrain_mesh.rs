@@ -1,10 +1,50 @@
-use bevy::prelude::*;
-use std::fs::File;
+use super::coord_utils::{tile_point_to_deg, tile_width_meters};
+use super::heightmap::*;
+use super::map_services::{TileContents, TileError};
+use super::quantized_mesh::*;
+use super::rtin::rtin_indices;
+use super::terrain_lod::SceneOrigin;
+use bevy::{ecs::system::SystemParam, prelude::*, render::texture::ImageType};
+use std::collections::{HashMap, HashSet};
 
 pub struct TerrainMeshOptions {
-    pub width: u32,
-    pub length: u32,
+    /// Vertices per side of the meshes, the heightmaps are resampled to it.
+    /// When None, a mesh has a vertex per point of its heightmap.
+    pub resolution: Option<u32>,
+    /// How far in meters the heightmap meshes may be from their heightmaps,
+    /// with fewer triangles where the ground is flatter. The grid is then
+    /// rounded up to a power of 2 plus 1 points a side. None for every
+    /// point of the grid.
+    pub max_error: Option<f32>,
+    /// Side of a tile in the scene.
+    pub tile_size: f32,
     pub height_scale: f32,
+    pub nodata: NoData,
+    /// How far down the skirts hang from the tiles' edges, in the scene,
+    /// to hide the cracks between neighbouring tiles. None for no skirts.
+    pub skirt_depth: Option<f32>,
+    /// How many zoom levels coarser the neighbouring tiles are, west, north,
+    /// east and south, so that the heightmap meshes follow their edges.
+    pub edge_levels: [u32; 4],
+}
+
+/// The number of vertices along each side of a grid mesh.
+#[derive(Debug, Clone, Copy)]
+struct Grid {
+    width: u32,
+    length: u32,
+}
+
+/// What to do with the points of a heightmap without data, e.g. over the
+/// ocean or in voids of the survey.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NoData {
+    /// Interpolated from the nearest points with data.
+    Interpolate,
+    /// Set to a fixed height in meters, e.g. 0 for sea level.
+    Height(f32),
+    /// Left out of the mesh, with the triangles they're part of.
+    Drop,
 }
 
 
@@ -12,11 +52,22 @@ pub struct TerrainMeshOptions {
 pub struct TileInfo {
     pub base_lat: f64,
     pub base_lon: f64,
-    pub x_offset: u32,
-    pub y_offset: u32,
+    pub x: u32,
+    pub y: u32,
     pub z: u32,
-    pub topo_filename: String,
-    pub image_filename: String,
+    /// Set when the tile is shown flat because its elevation couldn't be loaded.
+    pub topo_error: Option<TileError>,
+    /// Set when the tile is shown untextured because its imagery couldn't be loaded.
+    pub image_error: Option<TileError>,
+}
+
+/// The downloaded contents of a tile, only needed until its mesh is built.
+pub struct TileData {
+    pub topo: Result<TileContents, TileError>,
+    pub image: Result<TileContents, TileError>,
+    /// The elevation of the tiles west, north, east and south, for the
+    /// normals along the edges.
+    pub topo_neighbours: [Option<TileContents>; 4],
 }
 
 fn get_normal(v1: &[f32; 3], v2: &[f32; 3], v3: &[f32; 3]) -> [f32; 3] {
@@ -32,118 +83,546 @@ fn cross(a: &[f32; 3], b: &[f32; 3]) -> [f32; 3] {
     [nx, ny, nz]
 }
 
-fn get_height(x: u32, y: u32, heightmap: &lerc::LercDataset) -> f32 {
-    heightmap.data[x as usize + y as usize * heightmap.info.n_cols as usize] as f32
+fn get_height(x: u32, y: u32, heights: &[f32], grid: Grid) -> f32 {
+    heights[x as usize + y as usize * grid.width as usize]
 }
 
-fn sample_heightmap(
-    x: u32,
-    y: u32,
-    heightmap: &lerc::LercDataset,
-    mesh_options: &TerrainMeshOptions,
-) -> (f32, [f32; 3]) {
-    let height = get_height(x, y, heightmap) * mesh_options.height_scale;
-
-    let target = [0., height, 0.];
-    let right = [
-        1.,
-        if x >= (mesh_options.width - 1) {
-            0.
-        } else {
-            get_height(x + 1, y, heightmap) * mesh_options.height_scale
+/// Reads the heights under the mesh's vertices from the part of the heightmap
+/// covering the tile, which is all of it unless it's an ancestor's.
+fn grid_heights(heightmap: &Heightmap, topo: &TileContents, grid: Grid) -> Vec<f32> {
+    let mut heights = Vec::with_capacity((grid.width * grid.length) as usize);
+    for y in 0..grid.length {
+        for x in 0..grid.width {
+            let [u, v] = topo.uv([
+                x as f32 / (grid.width - 1) as f32,
+                y as f32 / (grid.length - 1) as f32,
+            ]);
+            heights.push(heightmap.sample(u, v));
+        }
+    }
+    heights
+}
+
+/// The mesh's grid, by default as many points as the heightmap has. An
+/// ancestor's heightmap is resampled to it too, so that the tile has as many
+/// vertices along its edges as its neighbours.
+fn heightmap_grid(heightmap: &Heightmap, mesh_options: &TerrainMeshOptions) -> Grid {
+    let grid = match mesh_options.resolution {
+        Some(resolution) => Grid {
+            width: resolution.max(2),
+            length: resolution.max(2),
         },
-        0.,
-    ];
-    let left = [
-        -1.,
-        if x <= 1 {
-            0.
-        } else {
-            get_height(x - 1, y, heightmap) * mesh_options.height_scale
+        None => Grid {
+            width: heightmap.n_cols as u32,
+            length: heightmap.n_rows as u32,
         },
-        0.,
-    ];
-    let top = [
-        0.,
-        if y >= (mesh_options.length - 1) {
-            0.
+    };
+    match mesh_options.max_error {
+        Some(_) => {
+            let size = (grid.width.max(grid.length) - 1).next_power_of_two() + 1;
+            Grid {
+                width: size,
+                length: size,
+            }
+        }
+        None => grid,
+    }
+}
+
+/// The normal at a point of the grid, from the slopes between the points
+/// around it. Past the tile's edges, the heights are read from `outside`,
+/// or the slope is taken from the edge point itself when it has none.
+/// `spacing` is the distance between the points east to west and north to
+/// south.
+fn grid_normal(
+    x: u32,
+    y: u32,
+    heights: &[f32],
+    grid: Grid,
+    spacing: [f32; 2],
+    outside: impl Fn(i64, i64) -> Option<f32>,
+) -> [f32; 3] {
+    let height = |x: i64, y: i64| {
+        if x >= 0 && y >= 0 && x < grid.width as i64 && y < grid.length as i64 {
+            Some(get_height(x as u32, y as u32, heights, grid))
         } else {
-            get_height(x, y + 1, heightmap) * mesh_options.height_scale
-        },
-        1.,
-    ];
-    let bottom = [
-        0.,
-        if y <= 1 {
-            0.
+            outside(x, y)
+        }
+    };
+    let (x, y) = (x as i64, y as i64);
+    let center = height(x, y).unwrap();
+    let slope = |(before_x, before_y): (i64, i64), (after_x, after_y): (i64, i64), spacing: f32| {
+        let (before, before_distance) = match height(before_x, before_y) {
+            Some(height) => (height, 1.),
+            None => (center, 0.),
+        };
+        let (after, after_distance) = match height(after_x, after_y) {
+            Some(height) => (height, 1.),
+            None => (center, 0.),
+        };
+        (after - before) / ((before_distance + after_distance) * spacing)
+    };
+    let slope_east = slope((x - 1, y), (x + 1, y), spacing[0]);
+    let slope_south = slope((x, y - 1), (x, y + 1), spacing[1]);
+    normalize([-slope_east, 1., -slope_south])
+}
+
+fn normalize(vector: [f32; 3]) -> [f32; 3] {
+    let length = (vector[0] * vector[0] + vector[1] * vector[1] + vector[2] * vector[2]).sqrt();
+    if length > 0. {
+        [vector[0] / length, vector[1] / length, vector[2] / length]
+    } else {
+        [0., 1., 0.]
+    }
+}
+
+/// Replaces the heights of the points without data, and returns which points
+/// had data.
+fn fill_nodata(heights: &mut [f32], grid: Grid, mesh_options: &TerrainMeshOptions) -> Vec<bool> {
+    let valid: Vec<bool> = heights.iter().map(|height| !height.is_nan()).collect();
+    match mesh_options.nodata {
+        NoData::Height(fill) => {
+            for height in heights.iter_mut().filter(|height| height.is_nan()) {
+                *height = fill;
+            }
+        }
+        // Dropped points still need a height for their neighbours' normals
+        NoData::Interpolate | NoData::Drop => interpolate_nodata(heights, grid),
+    }
+    valid
+}
+
+/// Fills the points without data from their neighbours with data, growing
+/// inwards from the edges of the gaps until they're closed. The gaps are
+/// filled breadth first, each ring of points from the one around it.
+fn interpolate_nodata(heights: &mut [f32], grid: Grid) {
+    let (width, length) = (grid.width as usize, grid.length as usize);
+    if heights.iter().all(|height| height.is_nan()) {
+        for height in heights.iter_mut() {
+            *height = 0.;
+        }
+        return;
+    }
+    let neighbours = |i: usize| {
+        let (x, y) = (i % width, i / width);
+        [
+            x.checked_sub(1).map(|x| x + y * width),
+            Some(x + 1).filter(|x| *x < width).map(|x| x + y * width),
+            y.checked_sub(1).map(|y| x + y * width),
+            Some(y + 1).filter(|y| *y < length).map(|y| x + y * width),
+        ]
+    };
+    let mut queued = vec![false; heights.len()];
+    let mut ring: Vec<usize> = (0..heights.len())
+        .filter(|i| heights[*i].is_nan())
+        .filter(|i| {
+            neighbours(*i)
+                .iter()
+                .flatten()
+                .any(|neighbour| !heights[*neighbour].is_nan())
+        })
+        .collect();
+    for i in &ring {
+        queued[*i] = true;
+    }
+    while !ring.is_empty() {
+        let filled: Vec<f32> = ring
+            .iter()
+            .map(|i| {
+                let (sum, count) = neighbours(*i)
+                    .iter()
+                    .flatten()
+                    .map(|neighbour| heights[*neighbour])
+                    .filter(|height| !height.is_nan())
+                    .fold((0., 0), |(sum, count), height| (sum + height, count + 1));
+                sum / count as f32
+            })
+            .collect();
+        for (i, height) in ring.iter().zip(filled) {
+            heights[*i] = height;
+        }
+        let mut next_ring = Vec::new();
+        for i in ring {
+            for neighbour in neighbours(i).iter().flatten() {
+                if heights[*neighbour].is_nan() && !queued[*neighbour] {
+                    queued[*neighbour] = true;
+                    next_ring.push(*neighbour);
+                }
+            }
+        }
+        ring = next_ring;
+    }
+}
+
+/// Builds a tile's mesh from its heightmap. The neighbouring tiles'
+/// heightmaps, west, north, east and south, are only read along the edges.
+pub fn mesh_from_heightmap(
+    topo: &TileContents,
+    neighbours: &[Option<TileContents>; 4],
+    format: HeightmapFormat,
+    mesh_options: &TerrainMeshOptions,
+) -> Result<(Vec<([f32; 3], [f32; 3], [f32; 2])>, Vec<u32>), TileError> {
+    let heightmap = decode_heightmap(format, topo)?;
+    let grid = heightmap_grid(&heightmap, mesh_options);
+    let mut heights = grid_heights(&heightmap, topo, grid);
+    let valid = fill_nodata(&mut heights, grid, mesh_options);
+    stitch_edges(&mut heights, grid, mesh_options.edge_levels);
+
+    // The heights past the edges, from the contents when they're an
+    // ancestor's covering them, or from the neighbouring tiles
+    let neighbours: Vec<Option<(&TileContents, Heightmap)>> = neighbours
+        .iter()
+        .map(|neighbour| {
+            let neighbour = neighbour.as_ref()?;
+            Some((neighbour, decode_heightmap(format, neighbour).ok()?))
+        })
+        .collect();
+    let outside = |x: i64, y: i64| {
+        let u = x as f32 / (grid.width - 1) as f32;
+        let v = y as f32 / (grid.length - 1) as f32;
+        let [contents_u, contents_v] = topo.uv([u, v]);
+        let height = if (0. ..=1.).contains(&contents_u) && (0. ..=1.).contains(&contents_v) {
+            heightmap.sample(contents_u, contents_v)
         } else {
-            get_height(x, y - 1, heightmap) * mesh_options.height_scale
-        },
-        -1.,
-    ];
+            let (side, u, v) = match (u, v) {
+                _ if u < 0. && (0. ..=1.).contains(&v) => (0, u + 1., v),
+                _ if v < 0. && (0. ..=1.).contains(&u) => (1, u, v + 1.),
+                _ if u > 1. && (0. ..=1.).contains(&v) => (2, u - 1., v),
+                _ if v > 1. && (0. ..=1.).contains(&u) => (3, u, v - 1.),
+                _ => return None,
+            };
+            let (contents, neighbour) = neighbours[side].as_ref()?;
+            let [u, v] = contents.uv([u, v]);
+            neighbour.sample(u, v)
+        };
+        Some(height).filter(|height| !height.is_nan())
+    };
 
-    let normal_1 = get_normal(&target, &top, &right);
-    let normal_2 = get_normal(&target, &bottom, &left);
-    let new_normal = [
-        (normal_1[0] + normal_2[0]) / 2.,
-        (normal_1[1] + normal_2[1]) / 2.,
-        (normal_1[2] + normal_2[2]) / 2.,
+    // The slopes are those of the ground, from its heights and the distance
+    // on the ground between the points, then scaled as the scene is
+    let (tile_x, tile_y, tile_z) = topo.tile;
+    let (lat, _) = tile_point_to_deg(
+        tile_x as f64 + (topo.uv_offset[0] + topo.uv_scale / 2.) as f64,
+        tile_y as f64 + (topo.uv_offset[1] + topo.uv_scale / 2.) as f64,
+        tile_z,
+    );
+    let tile_meters = tile_width_meters(lat, tile_z + topo.levels_up) as f32;
+    let spacing = [
+        tile_meters / (grid.width - 1) as f32,
+        tile_meters / (grid.length - 1) as f32,
     ];
+    let scene_per_meter = mesh_options.tile_size / tile_meters;
 
-    (height, new_normal)
+    let mut vertices_vec = Vec::new();
+    for y in 0..grid.length {
+        for x in 0..grid.width {
+            let height = get_height(x, y, &heights, grid) * mesh_options.height_scale;
+            let ground_normal = grid_normal(x, y, &heights, grid, spacing, &outside);
+            let normal = normalize([
+                ground_normal[0] / scene_per_meter,
+                ground_normal[1] / mesh_options.height_scale,
+                ground_normal[2] / scene_per_meter,
+            ]);
+            let uv = [
+                x as f32 / (grid.width - 1) as f32,
+                y as f32 / (grid.length - 1) as f32,
+            ];
+            let vertex = [
+                uv[0] * mesh_options.tile_size,
+                height,
+                uv[1] * mesh_options.tile_size,
+            ];
+            vertices_vec.push((vertex, normal, uv));
+        }
+    }
+
+    let mut indices_vec = match mesh_options.max_error {
+        Some(max_error) => {
+            // Dropped points must not end up under the triangles between
+            // the points around them
+            let keep = Some(&valid[..]).filter(|_| mesh_options.nodata == NoData::Drop);
+            rtin_indices(&heights, grid.width as usize, max_error, true, keep)
+        }
+        None => grid_indices(grid),
+    };
+    if mesh_options.nodata == NoData::Drop {
+        indices_vec = indices_vec
+            .chunks_exact(3)
+            .filter(|triangle| triangle.iter().all(|vertex| valid[*vertex as usize]))
+            .flatten()
+            .copied()
+            .collect();
+    }
+    if mesh_options.max_error.is_some() {
+        remove_unused_vertices(&mut vertices_vec, &mut indices_vec);
+    }
+    Ok((vertices_vec, indices_vec))
 }
 
-pub fn mesh_from_heightmap(
-    filename: &str,
-    mesh_options: TerrainMeshOptions,
-    scale_factor: f32,
-) -> Vec<([f32; 3], [f32; 3], [f32; 2])> {
-    let lerc_dataset = lerc::decode_file(File::open(filename).unwrap());
-
-    if let Ok(dataset) = lerc_dataset {
-        // println!(
-        //     "Info: {:?} / Data Range: {:?} / Data length: {}",
-        //     dataset.info,
-        //     dataset.data_range,
-        //     dataset.data.len()
-        // );
-
-        let mut vertices_vec = Vec::new();
-        for y in 0..(mesh_options.length - 0) {
-            for x in 0..(mesh_options.width - 0) {
-                let (height, normal) = sample_heightmap(x, y, &dataset, &mesh_options);
-                let vertex = [
-                    x as f32 * scale_factor,
-                    height,
-                    y as f32 * scale_factor,
-                ];
-                let uv = [
-                    x as f32 / mesh_options.width as f32,
-                    y as f32 / mesh_options.length as f32,
-                ];
-                vertices_vec.push((vertex, normal, uv));
+/// Leaves out the vertices of the grid that no triangle uses.
+fn remove_unused_vertices(
+    vertices_vec: &mut Vec<([f32; 3], [f32; 3], [f32; 2])>,
+    indices_vec: &mut [u32],
+) {
+    let mut new_indices = vec![None; vertices_vec.len()];
+    let mut used_vertices = Vec::new();
+    for index in indices_vec.iter_mut() {
+        let vertex = *index as usize;
+        *index = *new_indices[vertex].get_or_insert_with(|| {
+            used_vertices.push(vertices_vec[vertex]);
+            (used_vertices.len() - 1) as u32
+        });
+    }
+    *vertices_vec = used_vertices;
+}
+
+/// Moves the vertices along the edges shared with coarser tiles onto the
+/// lines between the vertices the coarser tiles have, so that no cracks open
+/// between them. Neighbours are assumed to have as many vertices per tile
+/// along their edges, as they do with the same source and settings, the
+/// skirts being the only guarantee against cracks otherwise.
+fn stitch_edges(heights: &mut [f32], grid: Grid, edge_levels: [u32; 4]) {
+    let (width, length) = (grid.width as usize, grid.length as usize);
+    let west: Vec<usize> = (0..length).map(|y| y * width).collect();
+    let north: Vec<usize> = (0..width).collect();
+    let east: Vec<usize> = (0..length).map(|y| width - 1 + y * width).collect();
+    let south: Vec<usize> = (0..width).map(|x| x + (length - 1) * width).collect();
+    for (edge, levels) in [west, north, east, south].iter().zip(&edge_levels) {
+        let step = 1_usize << (*levels).min(16);
+        if step == 1 {
+            continue;
+        }
+        for start in (0..edge.len() - 1).step_by(step) {
+            let end = (start + step).min(edge.len() - 1);
+            let (start_height, end_height) = (heights[edge[start]], heights[edge[end]]);
+            for i in start + 1..end {
+                let t = (i - start) as f32 / (end - start) as f32;
+                heights[edge[i]] = start_height + (end_height - start_height) * t;
             }
         }
-        vertices_vec
-    } else {
-        println!("Failed to read {}", filename);
-        Vec::new()
     }
 }
 
-const WIDTH: u32 = 257;
-const LENGTH: u32 = 257;
+/// Hangs a strip down from the outline of the tile's triangles, hiding the
+/// cracks with the neighbouring tiles where their edges don't meet exactly.
+/// The outline is the tile's edges, or past them for an ancestor's
+/// quantized-mesh triangles, which aren't cut to the tile.
+fn add_skirts(
+    vertices_vec: &mut Vec<([f32; 3], [f32; 3], [f32; 2])>,
+    indices_vec: &mut Vec<u32>,
+    depth: f32,
+) {
+    // Triangles are counterclockwise seen from above, so an edge between
+    // two of them goes one way in one and the other way in the other, and
+    // the outside is on the right going from one vertex of an outline edge
+    // to the next
+    let triangle_edges = |triangle: &[u32]| {
+        let triangle = [triangle[0], triangle[1], triangle[2]];
+        (0..3).map(move |i| (triangle[i], triangle[(i + 1) % 3]))
+    };
+    let all_edges: HashSet<(u32, u32)> = indices_vec
+        .chunks_exact(3)
+        .flat_map(triangle_edges)
+        .collect();
+    // Where points without data were dropped, the gaps within the tile are
+    // left open
+    let within_tile = |vertex: u32| {
+        let uv = vertices_vec[vertex as usize].2;
+        uv.iter().all(|uv| *uv > 1e-5 && *uv < 1. - 1e-5)
+    };
+    let edges: Vec<(u32, u32)> = indices_vec
+        .chunks_exact(3)
+        .flat_map(triangle_edges)
+        .filter(|(a, b)| !all_edges.contains(&(*b, *a)))
+        .filter(|(a, b)| !(within_tile(*a) && within_tile(*b)))
+        .collect();
+
+    let mut lowered_vertices = HashMap::new();
+    let mut lowered = |vertex: u32, vertices_vec: &mut Vec<([f32; 3], [f32; 3], [f32; 2])>| {
+        *lowered_vertices.entry(vertex).or_insert_with(|| {
+            let (mut position, normal, uv) = vertices_vec[vertex as usize];
+            position[1] -= depth;
+            vertices_vec.push((position, normal, uv));
+            (vertices_vec.len() - 1) as u32
+        })
+    };
+    for (a, b) in edges {
+        let (lowered_a, lowered_b) = (lowered(a, vertices_vec), lowered(b, vertices_vec));
+        indices_vec.extend_from_slice(&[b, a, lowered_a, b, lowered_a, lowered_b]);
+    }
+}
+
+/// Builds a tile's mesh from its quantized-mesh triangles. Unlike heightmaps,
+/// an ancestor's mesh isn't cut to the tile: its triangles overlapping the
+/// tile are kept whole.
+pub fn mesh_from_quantized_mesh(
+    topo: &TileContents,
+    mesh_options: &TerrainMeshOptions,
+) -> Result<(Vec<([f32; 3], [f32; 3], [f32; 2])>, Vec<u32>), TileError> {
+    let quantized_mesh = decode_quantized_mesh(&topo.data)?;
+
+    // Positions within the tile, the contents' v going north
+    let tile_uv: Vec<[f32; 2]> = quantized_mesh
+        .uv
+        .iter()
+        .map(|[u, v]| {
+            [
+                (u - topo.uv_offset[0]) / topo.uv_scale,
+                (1. - v - topo.uv_offset[1]) / topo.uv_scale,
+            ]
+        })
+        .collect();
+    let positions: Vec<[f32; 3]> = tile_uv
+        .iter()
+        .zip(&quantized_mesh.heights)
+        .map(|([u, v], height)| {
+            [
+                u * mesh_options.tile_size,
+                height * mesh_options.height_scale,
+                v * mesh_options.tile_size,
+            ]
+        })
+        .collect();
+
+    // Triangles entirely past one of the tile's edges are left out
+    let past_edges = |index: &u32| {
+        let [u, v] = tile_uv[*index as usize];
+        [u < 0., u > 1., v < 0., v > 1.]
+    };
+    let mut indices = Vec::with_capacity(quantized_mesh.indices.len());
+    for triangle in quantized_mesh.indices.chunks_exact(3) {
+        let past: Vec<[bool; 4]> = triangle.iter().map(past_edges).collect();
+        if !(0..4).any(|edge| past.iter().all(|vertex| vertex[edge])) {
+            indices.extend_from_slice(triangle);
+        }
+    }
+
+    let normals: Vec<[f32; 3]> = match &quantized_mesh.normals {
+        Some(normals) => {
+            // From Earth-centered, Earth-fixed to the scene's east, up, south
+            let (x, y, z) = topo.tile;
+            let (lat, lon) = tile_point_to_deg(x as f64 + 0.5, y as f64 + 0.5, z);
+            let (lat, lon) = (lat.to_radians() as f32, lon.to_radians() as f32);
+            let east = [-lon.sin(), lon.cos(), 0.];
+            let north = [-lat.sin() * lon.cos(), -lat.sin() * lon.sin(), lat.cos()];
+            let up = [lat.cos() * lon.cos(), lat.cos() * lon.sin(), lat.sin()];
+            let dot = |a: &[f32; 3], b: &[f32; 3]| a[0] * b[0] + a[1] * b[1] + a[2] * b[2];
+            normals
+                .iter()
+                .map(|normal| [dot(normal, &east), dot(normal, &up), -dot(normal, &north)])
+                .collect()
+        }
+        None => {
+            let mut normals = vec![[0., 0., 0.]; positions.len()];
+            for triangle in indices.chunks_exact(3) {
+                let position = |i: usize| &positions[triangle[i] as usize];
+                let normal = get_normal(position(0), position(1), position(2));
+                for index in triangle {
+                    for i in 0..3 {
+                        normals[*index as usize][i] += normal[i];
+                    }
+                }
+            }
+            normals.into_iter().map(normalize).collect()
+        }
+    };
+
+    let vertices_vec = positions
+        .into_iter()
+        .zip(normals)
+        .zip(tile_uv)
+        .map(|((position, normal), uv)| (position, normal, uv))
+        .collect();
+    Ok((vertices_vec, indices))
+}
+
+/// Two triangles per cell of a grid of vertices.
+fn grid_indices(grid: Grid) -> Vec<u32> {
+    let width = grid.width;
+    let mut indices_vec = Vec::new();
+    for y in 0..(grid.length - 1) {
+        for x in 0..(width - 1) {
+            indices_vec.push(x + y * width);
+            indices_vec.push(x + (y + 1) * width);
+            indices_vec.push(x + 1 + y * width);
+
+            indices_vec.push(x + 1 + y * width);
+            indices_vec.push(x + (y + 1) * width);
+            indices_vec.push(x + 1 + (y + 1) * width);
+        }
+    }
+    indices_vec
+}
+
+/// Placeholder for tiles without elevation data.
+fn flat_mesh(mesh_options: &TerrainMeshOptions) -> (Vec<([f32; 3], [f32; 3], [f32; 2])>, Vec<u32>) {
+    let resolution = mesh_options.resolution.unwrap_or(2).max(2);
+    let grid = Grid {
+        width: resolution,
+        length: resolution,
+    };
+    let mut vertices_vec = Vec::new();
+    for y in 0..grid.length {
+        for x in 0..grid.width {
+            let uv = [
+                x as f32 / (grid.width - 1) as f32,
+                y as f32 / (grid.length - 1) as f32,
+            ];
+            let vertex = [
+                uv[0] * mesh_options.tile_size,
+                0.,
+                uv[1] * mesh_options.tile_size,
+            ];
+            vertices_vec.push((vertex, [0., 1., 0.], uv));
+        }
+    }
+    (vertices_vec, grid_indices(grid))
+}
+
+/// Scene units per meter of elevation.
+pub const HEIGHT_SCALE: f32 = 0.002;
+
+/// The assets the tiles' meshes are made of.
+#[derive(SystemParam)]
+pub struct TerrainAssets<'a> {
+    pub meshes: ResMut<'a, Assets<Mesh>>,
+    pub materials: ResMut<'a, Assets<StandardMaterial>>,
+    pub textures: ResMut<'a, Assets<Texture>>,
+}
 
 pub fn setup_terrain(
     commands: &mut Commands,
-    meshes: &mut ResMut<Assets<Mesh>>,
-    materials: &mut ResMut<Assets<StandardMaterial>>,
-    asset_server: &Res<AssetServer>,
-    tile_info: TileInfo
+    assets: &mut TerrainAssets,
+    image_format: &str,
+    heightmap_format: HeightmapFormat,
+    mut tile_info: TileInfo,
+    tile_data: TileData,
+    mesh_options: &TerrainMeshOptions,
 ) {
-    let texture_handle: Handle<Texture> = asset_server.load(&tile_info.image_filename[7..]);
-    let material_handle = materials.add(StandardMaterial {
-        base_color_texture: Some(texture_handle),
+    let texture = tile_data.image.and_then(|image| {
+        Texture::from_buffer(&image.data, ImageType::Extension(image_format))
+            .map(|texture| (texture, image))
+            .map_err(|error| TileError::Decode(error.to_string()))
+    });
+    let mut image_contents = None;
+    let texture_handle = match texture {
+        Ok((texture, image)) => {
+            image_contents = Some(image);
+            Some(assets.textures.add(texture))
+        }
+        Err(error) => {
+            println!("No imagery for tile {:?}: {}", tile_info, error);
+            tile_info.image_error = Some(error);
+            None
+        }
+    };
+    let material_handle = assets.materials.add(StandardMaterial {
+        base_color: if texture_handle.is_some() {
+            Color::WHITE
+        } else {
+            Color::GRAY
+        },
+        base_color_texture: texture_handle,
         roughness: 1.,
         metallic: 0.,
         reflectance: 0.,
@@ -151,30 +630,26 @@ pub fn setup_terrain(
         ..Default::default()
     });
 
-    let n = 1. / ((tile_info.z - 9) * (tile_info.z - 9)) as f32;
-    let scale_factor = 0.3 * n;
+    let origin = SceneOrigin::new(tile_info.base_lat, tile_info.base_lon);
+    let (corner, _) = origin.tile_rect(tile_info.x, tile_info.y, tile_info.z);
 
-    let vertices_vec = mesh_from_heightmap(
-        &tile_info.topo_filename,
-        TerrainMeshOptions {
-            width: LENGTH,
-            length: WIDTH,
-            height_scale: 0.002,
-        },
-        scale_factor,
-    );
-
-    let mut indices_vec = Vec::new();
-    for y in 0..(LENGTH - 1) {
-        for x in 0..(WIDTH - 1) {
-            indices_vec.push(x + y * LENGTH);
-            indices_vec.push(x + (y + 1) * LENGTH);
-            indices_vec.push(x + 1 + y * LENGTH);
-
-            indices_vec.push(x + 1 + y * LENGTH);
-            indices_vec.push(x + (y + 1) * LENGTH);
-            indices_vec.push(x + 1 + (y + 1) * LENGTH);
+    let topo_neighbours = tile_data.topo_neighbours;
+    let mesh = tile_data.topo.and_then(|topo| match heightmap_format {
+        HeightmapFormat::QuantizedMesh => mesh_from_quantized_mesh(&topo, mesh_options),
+        _ => mesh_from_heightmap(&topo, &topo_neighbours, heightmap_format, mesh_options),
+    });
+    let (mut vertices_vec, mut indices_vec) = match mesh {
+        Ok(mesh) => mesh,
+        Err(error) => {
+            if !matches!(error, TileError::NotFound) {
+                println!("No elevation for tile {:?}: {}", tile_info, error);
+            }
+            tile_info.topo_error = Some(error);
+            flat_mesh(mesh_options)
         }
+    };
+    if let Some(depth) = mesh_options.skirt_depth {
+        add_skirts(&mut vertices_vec, &mut indices_vec, depth);
     }
 
     let indices = bevy::render::mesh::Indices::U32(indices_vec);
@@ -185,7 +660,10 @@ pub fn setup_terrain(
     for (position, normal, uv) in vertices_vec.iter() {
         positions.push(*position);
         normals.push(*normal);
-        uvs.push(*uv);
+        uvs.push(match &image_contents {
+            Some(image) => image.uv(*uv),
+            None => *uv,
+        });
     }
 
     let mut mesh = Mesh::new(bevy::render::pipeline::PrimitiveTopology::TriangleList);
@@ -197,16 +675,10 @@ pub fn setup_terrain(
     commands
         .spawn_bundle(PbrBundle {
             transform: Transform {
-                translation: Vec3::new(
-                    ((tile_info.x_offset * (WIDTH - 1)) as f32 * scale_factor)
-                        - (WIDTH as f32 * 0.5 * 0.3),
-                    0.,
-                    (tile_info.y_offset * (LENGTH - 1)) as f32 * scale_factor
-                        - (LENGTH as f32 * 0.5 * 0.3),
-                ),
+                translation: Vec3::new(corner.x, 0., corner.y),
                 ..Default::default()
             },
-            mesh: meshes.add(mesh),
+            mesh: assets.meshes.add(mesh),
             material: material_handle,
             ..Default::default()
         })
@@ -0,0 +1,584 @@
+use super::coord_utils::TileRegion;
+use super::map_services::{TileError, TileSource};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How long a tile is used without revalidation when the server didn't say.
+const DEFAULT_MAX_AGE: u64 = 7 * 24 * 60 * 60;
+
+pub fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// What the server told us about a tile, used to revalidate it once stale.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TileMetadata {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// From `Cache-Control`, 0 when the tile must always be revalidated.
+    pub max_age: Option<u64>,
+    /// Unix time at which the tile was downloaded or last revalidated.
+    pub fetched_at: u64,
+}
+
+impl TileMetadata {
+    pub fn is_fresh(&self, now: u64) -> bool {
+        now < self
+            .fetched_at
+            .saturating_add(self.max_age.unwrap_or(DEFAULT_MAX_AGE))
+    }
+
+    fn to_json(&self) -> String {
+        serde_json::json!({
+            "etag": self.etag,
+            "last_modified": self.last_modified,
+            "max_age": self.max_age,
+            "fetched_at": self.fetched_at,
+        })
+        .to_string()
+    }
+
+    fn from_json(json: &[u8]) -> Option<TileMetadata> {
+        let value: serde_json::Value = serde_json::from_slice(json).ok()?;
+        Some(TileMetadata {
+            etag: value["etag"].as_str().map(|etag| etag.to_string()),
+            last_modified: value["last_modified"].as_str().map(|date| date.to_string()),
+            max_age: value["max_age"].as_u64(),
+            fetched_at: value["fetched_at"].as_u64()?,
+        })
+    }
+}
+
+pub struct CachedTile {
+    pub data: Vec<u8>,
+    pub metadata: TileMetadata,
+}
+
+/// The space taken by the tiles of one layer at one zoom level.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheUsage {
+    pub layer: String,
+    pub zoom: u32,
+    pub tiles: u64,
+    pub bytes: u64,
+}
+
+/// Keeps downloaded tiles so that they are only fetched once, or again when
+/// they are stale and the server has a newer version.
+pub trait TileCache: Send + Sync {
+    fn get(
+        &self,
+        source: &dyn TileSource,
+        x: u32,
+        y: u32,
+        z: u32,
+    ) -> Result<Option<CachedTile>, TileError>;
+
+    fn put(
+        &self,
+        source: &dyn TileSource,
+        x: u32,
+        y: u32,
+        z: u32,
+        data: &[u8],
+        metadata: &TileMetadata,
+    ) -> Result<(), TileError>;
+
+    /// Updates the metadata of a cached tile the server says is still current.
+    fn put_metadata(
+        &self,
+        source: &dyn TileSource,
+        x: u32,
+        y: u32,
+        z: u32,
+        metadata: &TileMetadata,
+    ) -> Result<(), TileError>;
+
+    /// Sorted by layer and zoom level.
+    fn usage(&self) -> Result<Vec<CacheUsage>, TileError>;
+}
+
+struct CacheEntry {
+    layer: String,
+    z: u32,
+    /// The tile and its metadata file.
+    bytes: u64,
+    /// Pinned tiles are never evicted, so they're left out of the LRU order.
+    pinned: bool,
+    /// The tile's key in the LRU order, increasing with each access.
+    access: u64,
+}
+
+/// The tiles in a `FileCache` directory, keyed by filename.
+#[derive(Default)]
+struct CacheIndex {
+    entries: HashMap<String, CacheEntry>,
+    /// The filenames of the tiles that may be evicted, least recently used
+    /// first.
+    lru: BTreeMap<u64, String>,
+    next_access: u64,
+    total_bytes: u64,
+    /// Set once the cache couldn't be brought within its budget, so that
+    /// it's only reported once.
+    over_budget_reported: bool,
+}
+
+impl CacheIndex {
+    /// Lists the tiles already on disk. Access times aren't kept between
+    /// runs, the modification time of the files is used instead.
+    fn scan(directory: &str, is_pinned: impl Fn(u32, u32, u32) -> bool) -> CacheIndex {
+        let mut index = CacheIndex::default();
+        let files = match fs::read_dir(directory) {
+            Ok(files) => files,
+            Err(_) => return index,
+        };
+        let mut tiles = Vec::new();
+        for file in files.filter_map(|file| file.ok()) {
+            let name = file.file_name().to_string_lossy().to_string();
+            let (layer, x, y, z) = match parse_tile_filename(&name) {
+                Some(tile) => tile,
+                None => continue,
+            };
+            let metadata = match file.metadata() {
+                Ok(metadata) if metadata.is_file() => metadata,
+                _ => continue,
+            };
+            let filename = format!("{}/{}", directory, name);
+            let metadata_bytes = fs::metadata(format!("{}.json", filename))
+                .map(|metadata| metadata.len())
+                .unwrap_or(0);
+            let modified = metadata.modified().unwrap_or_else(|_| SystemTime::now());
+            let bytes = metadata.len() + metadata_bytes;
+            tiles.push((modified, filename, layer, x, y, z, bytes));
+        }
+        tiles.sort_by_key(|tile| tile.0);
+        for (_, filename, layer, x, y, z, bytes) in tiles {
+            let pinned = is_pinned(x, y, z);
+            index.insert(filename, layer, z, bytes, pinned);
+        }
+        index
+    }
+
+    /// Adds a tile, or updates it, as the most recently used.
+    fn insert(&mut self, filename: String, layer: String, z: u32, bytes: u64, pinned: bool) {
+        self.remove(&filename);
+        let access = self.next_access;
+        self.next_access += 1;
+        if !pinned {
+            self.lru.insert(access, filename.clone());
+        }
+        self.total_bytes += bytes;
+        self.entries.insert(
+            filename,
+            CacheEntry {
+                layer,
+                z,
+                bytes,
+                pinned,
+                access,
+            },
+        );
+    }
+
+    fn remove(&mut self, filename: &str) {
+        if let Some(entry) = self.entries.remove(filename) {
+            self.total_bytes -= entry.bytes;
+            if !entry.pinned {
+                self.lru.remove(&entry.access);
+            }
+        }
+    }
+}
+
+/// Splits `<layer>_<x>_<y>_<z>.<extension>`, layer names may contain `_`.
+fn parse_tile_filename(name: &str) -> Option<(String, u32, u32, u32)> {
+    let (stem, extension) = name.rsplit_once('.')?;
+    if extension == "json" || name.starts_with('.') {
+        return None;
+    }
+    let mut parts = stem.rsplitn(4, '_');
+    let z = parts.next()?.parse().ok()?;
+    let y = parts.next()?.parse().ok()?;
+    let x = parts.next()?.parse().ok()?;
+    let layer = parts.next()?.to_string();
+    Some((layer, x, y, z))
+}
+
+/// Where tiles used to be cached, relative to the working directory.
+pub const LEGACY_CACHE_DIR: &str = "assets/images";
+
+/// Where tiles are cached unless told otherwise: `$RUST_MAPS_CACHE_DIR`, or
+/// a `rust_maps` directory in the user's cache directory, so that it doesn't
+/// depend on the working directory. A cache left in the old directory keeps
+/// being used, so that its tiles aren't downloaded again.
+pub fn default_cache_dir() -> String {
+    if let Ok(directory) = std::env::var("RUST_MAPS_CACHE_DIR") {
+        return directory;
+    }
+    if Path::new(LEGACY_CACHE_DIR).is_dir() {
+        return LEGACY_CACHE_DIR.to_string();
+    }
+    let user_cache = std::env::var("XDG_CACHE_HOME")
+        .or_else(|_| std::env::var("LOCALAPPDATA"))
+        .or_else(|_| std::env::var("HOME").map(|home| format!("{}/.cache", home)));
+    match user_cache {
+        Ok(directory) => format!("{}/rust_maps", directory),
+        Err(_) => LEGACY_CACHE_DIR.to_string(),
+    }
+}
+
+/// Stores each tile in its own file, named after the source and the tile,
+/// with its metadata next to it in a `.json` file.
+pub struct FileCache {
+    pub directory: String,
+    /// Once the files take more than this, the least recently used tiles are
+    /// deleted.
+    pub max_bytes: Option<u64>,
+    /// Tiles in these regions are never deleted.
+    pub pinned_regions: Vec<TileRegion>,
+    /// Built on first use.
+    index: Mutex<Option<CacheIndex>>,
+}
+
+impl FileCache {
+    pub fn new(directory: &str) -> FileCache {
+        FileCache {
+            directory: directory.to_string(),
+            max_bytes: None,
+            pinned_regions: Vec::new(),
+            index: Mutex::new(None),
+        }
+    }
+
+    fn with_index<T>(&self, f: impl FnOnce(&mut CacheIndex) -> T) -> T {
+        let mut index = self.index.lock().unwrap();
+        f(index.get_or_insert_with(|| {
+            CacheIndex::scan(&self.directory, |x, y, z| self.is_pinned(x, y, z))
+        }))
+    }
+
+    fn is_pinned(&self, x: u32, y: u32, z: u32) -> bool {
+        self.pinned_regions
+            .iter()
+            .any(|region| region.contains(x, y, z))
+    }
+
+    /// Records that a tile was read or written, then makes room if needed.
+    fn touch(&self, source: &dyn TileSource, x: u32, y: u32, z: u32, bytes: u64) {
+        let filename = self.tile_filename(source, x, y, z);
+        let pinned = self.is_pinned(x, y, z);
+        self.with_index(|index| {
+            let layer = source.name().to_string();
+            index.insert(filename.clone(), layer, z, bytes, pinned);
+            self.evict(index, &filename);
+        });
+    }
+
+    /// Deletes the least recently used tiles until the cache fits in its
+    /// budget, except for the pinned ones and the tile just used.
+    fn evict(&self, index: &mut CacheIndex, just_used: &str) {
+        let max_bytes = match self.max_bytes {
+            Some(max_bytes) => max_bytes,
+            None => return,
+        };
+        while index.total_bytes > max_bytes {
+            // The tile just used is the most recent, so it's only reached
+            // once there's nothing else left to evict
+            let filename = match index.lru.values().next() {
+                Some(filename) if filename != just_used => filename.clone(),
+                _ => {
+                    if !index.over_budget_reported {
+                        println!(
+                            "The file cache takes {} MB, more than its {} MB, but only pinned tiles are left to evict",
+                            index.total_bytes / (1024 * 1024),
+                            max_bytes / (1024 * 1024)
+                        );
+                        index.over_budget_reported = true;
+                    }
+                    return;
+                }
+            };
+            for file in &[filename.clone(), format!("{}.json", filename)] {
+                if let Err(error) = fs::remove_file(file) {
+                    if error.kind() != ErrorKind::NotFound {
+                        println!("Failed to evict {}: {}", file, error);
+                    }
+                }
+            }
+            index.remove(&filename);
+        }
+    }
+
+    pub fn tile_filename(&self, source: &dyn TileSource, x: u32, y: u32, z: u32) -> String {
+        format!(
+            "{}/{}_{}_{}_{}.{}",
+            self.directory,
+            source.name(),
+            x,
+            y,
+            z,
+            source.file_extension()
+        )
+    }
+
+    fn metadata_filename(&self, source: &dyn TileSource, x: u32, y: u32, z: u32) -> String {
+        format!("{}.json", self.tile_filename(source, x, y, z))
+    }
+
+    /// Written next to the file and renamed, so a file is always complete.
+    fn write_file(&self, filename: &str, data: &[u8]) -> Result<(), TileError> {
+        fs::create_dir_all(&self.directory)?;
+        let mut file = tempfile::NamedTempFile::new_in(&self.directory)?;
+        file.write_all(data)?;
+        file.persist(filename).map_err(|error| error.error)?;
+        Ok(())
+    }
+}
+
+impl TileCache for FileCache {
+    fn get(
+        &self,
+        source: &dyn TileSource,
+        x: u32,
+        y: u32,
+        z: u32,
+    ) -> Result<Option<CachedTile>, TileError> {
+        let filename = self.tile_filename(source, x, y, z);
+        let data = match fs::read(&filename) {
+            Ok(data) => data,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        let json = fs::read(self.metadata_filename(source, x, y, z)).ok();
+        // Tiles cached before metadata was kept have no metadata file
+        let metadata_bytes = json.as_ref().map_or(0, |json| json.len() as u64);
+        let metadata = json
+            .and_then(|json| TileMetadata::from_json(&json))
+            .unwrap_or_else(|| legacy_metadata(Path::new(&filename)));
+        self.touch(source, x, y, z, data.len() as u64 + metadata_bytes);
+        Ok(Some(CachedTile { data, metadata }))
+    }
+
+    fn put(
+        &self,
+        source: &dyn TileSource,
+        x: u32,
+        y: u32,
+        z: u32,
+        data: &[u8],
+        metadata: &TileMetadata,
+    ) -> Result<(), TileError> {
+        self.write_file(&self.tile_filename(source, x, y, z), data)?;
+        self.put_metadata(source, x, y, z, metadata)?;
+        let metadata_bytes = metadata.to_json().len() as u64;
+        self.touch(source, x, y, z, data.len() as u64 + metadata_bytes);
+        Ok(())
+    }
+
+    fn put_metadata(
+        &self,
+        source: &dyn TileSource,
+        x: u32,
+        y: u32,
+        z: u32,
+        metadata: &TileMetadata,
+    ) -> Result<(), TileError> {
+        let filename = self.metadata_filename(source, x, y, z);
+        self.write_file(&filename, metadata.to_json().as_bytes())
+    }
+
+    fn usage(&self) -> Result<Vec<CacheUsage>, TileError> {
+        let mut usage: BTreeMap<(String, u32), (u64, u64)> = BTreeMap::new();
+        self.with_index(|index| {
+            for entry in index.entries.values() {
+                let layer_usage = usage
+                    .entry((entry.layer.clone(), entry.z))
+                    .or_insert((0, 0));
+                layer_usage.0 += 1;
+                layer_usage.1 += entry.bytes;
+            }
+        });
+        Ok(usage
+            .into_iter()
+            .map(|((layer, zoom), (tiles, bytes))| CacheUsage {
+                layer,
+                zoom,
+                tiles,
+                bytes,
+            })
+            .collect())
+    }
+}
+
+/// Tiles cached before metadata was kept are treated as fetched when their
+/// file was written, without any validators.
+fn legacy_metadata(filename: &Path) -> TileMetadata {
+    let fetched_at = fs::metadata(filename)
+        .and_then(|metadata| metadata.modified())
+        .map(unix_time)
+        .unwrap_or(0);
+    TileMetadata {
+        fetched_at,
+        ..Default::default()
+    }
+}
+
+/// How far from the end of a JPEG its end marker is looked for.
+const JPEG_TRAILER_LENGTH: usize = 256;
+
+/// Cheap structural checks for the tile formats we know, meant to catch
+/// truncated files and error pages saved as tiles rather than fully decode
+/// them. Unknown formats are assumed to be valid.
+pub fn is_valid_tile(file_extension: &str, data: &[u8]) -> bool {
+    match file_extension {
+        // Some encoders pad JPEGs or append metadata past their end marker.
+        // The marker can't appear in the image data, where 0xff bytes are
+        // followed by 0 or a restart marker.
+        "jpeg" | "jpg" => {
+            let trailer = &data[data.len().saturating_sub(JPEG_TRAILER_LENGTH)..];
+            data.len() >= 4
+                && data.starts_with(&[0xff, 0xd8])
+                && trailer.windows(2).any(|marker| *marker == [0xff, 0xd9])
+        }
+        "png" => {
+            data.starts_with(b"\x89PNG\r\n\x1a\n")
+                && data.len() >= 12
+                && &data[data.len() - 8..data.len() - 4] == b"IEND"
+        }
+        "webp" => {
+            data.len() >= 12
+                && data.starts_with(b"RIFF")
+                && &data[8..12] == b"WEBP"
+                && u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize + 8 <= data.len()
+        }
+        "lerc" => is_valid_lerc(data),
+        "tiff" | "tif" => data.starts_with(b"II*\0") || data.starts_with(b"MM\0*"),
+        _ => true,
+    }
+}
+
+/// LERC2 blobs start with their own size, older LERC1 blobs only have a magic
+/// string to check.
+fn is_valid_lerc(data: &[u8]) -> bool {
+    if data.starts_with(b"CntZImage ") {
+        return true;
+    }
+    if !data.starts_with(b"Lerc2 ") || data.len() < 10 {
+        return false;
+    }
+    let int_at = |offset: usize| {
+        data.get(offset..offset + 4)
+            .map(|bytes| i32::from_le_bytes(bytes.try_into().unwrap()))
+    };
+    let version = int_at(6).unwrap_or(0);
+    // After the version: a checksum since v3, then nRows, nCols, nDim since v4,
+    // numValidPixel, microBlockSize and blobSize
+    let checksum_size = if version >= 3 { 4 } else { 0 };
+    let n_ints = if version >= 4 { 5 } else { 4 };
+    match int_at(10 + checksum_size + 4 * n_ints) {
+        Some(blob_size) => blob_size > 0 && blob_size as usize <= data.len(),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map_services::UrlTemplateSource;
+
+    fn test_source() -> UrlTemplateSource {
+        UrlTemplateSource {
+            name: "test".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn tile_exists(cache: &FileCache, x: u32, y: u32, z: u32) -> bool {
+        Path::new(&cache.tile_filename(&test_source(), x, y, z)).exists()
+    }
+
+    const DATA: [u8; 100] = [0; 100];
+
+    /// The bytes a tile written with `DATA` takes with its metadata.
+    fn tile_bytes() -> u64 {
+        (DATA.len() + TileMetadata::default().to_json().len()) as u64
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut cache = FileCache::new(directory.path().to_str().unwrap());
+        cache.max_bytes = Some(3 * tile_bytes());
+        let source = test_source();
+        let metadata = TileMetadata::default();
+        for x in 0..3 {
+            cache.put(&source, x, 0, 2, &DATA, &metadata).unwrap();
+        }
+        assert!(cache.get(&source, 0, 0, 2).unwrap().is_some());
+        cache.put(&source, 3, 0, 2, &DATA, &metadata).unwrap();
+
+        assert!(tile_exists(&cache, 0, 0, 2));
+        assert!(!tile_exists(&cache, 1, 0, 2));
+        assert!(!Path::new(&cache.metadata_filename(&source, 1, 0, 2)).exists());
+        assert!(tile_exists(&cache, 2, 0, 2));
+        assert!(tile_exists(&cache, 3, 0, 2));
+        let usage = cache.usage().unwrap();
+        assert_eq!(usage.len(), 1);
+        assert_eq!((usage[0].tiles, usage[0].bytes), (3, 3 * tile_bytes()));
+
+        // Rescanned from the files, oldest first
+        let mut cache = FileCache::new(directory.path().to_str().unwrap());
+        cache.max_bytes = Some(3 * tile_bytes());
+        assert_eq!(cache.usage().unwrap()[0].bytes, 3 * tile_bytes());
+    }
+
+    #[test]
+    fn keeps_pinned_tiles() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut cache = FileCache::new(directory.path().to_str().unwrap());
+        cache.max_bytes = Some(2 * tile_bytes());
+        // The north west tile at zoom 1
+        cache.pinned_regions.push(TileRegion {
+            west: -170.,
+            south: 10.,
+            east: -10.,
+            north: 80.,
+            min_zoom: 1,
+            max_zoom: 1,
+        });
+        let source = test_source();
+        let metadata = TileMetadata::default();
+        cache.put(&source, 0, 0, 1, &DATA, &metadata).unwrap();
+        cache.put(&source, 1, 0, 1, &DATA, &metadata).unwrap();
+        cache.put(&source, 0, 1, 1, &DATA, &metadata).unwrap();
+        assert!(tile_exists(&cache, 0, 0, 1));
+        assert!(!tile_exists(&cache, 1, 0, 1));
+        assert!(tile_exists(&cache, 0, 1, 1));
+
+        // Only the pinned tile and the one just used are left, so the cache
+        // stays over its budget
+        cache.max_bytes = Some(tile_bytes());
+        cache.put(&source, 1, 1, 1, &DATA, &metadata).unwrap();
+        assert!(tile_exists(&cache, 0, 0, 1));
+        assert!(!tile_exists(&cache, 0, 1, 1));
+        assert!(tile_exists(&cache, 1, 1, 1));
+    }
+
+    #[test]
+    fn counts_missing_metadata_as_empty() {
+        let directory = tempfile::tempdir().unwrap();
+        let cache = FileCache::new(directory.path().to_str().unwrap());
+        let source = test_source();
+        fs::write(cache.tile_filename(&source, 0, 0, 0), DATA).unwrap();
+        let tile = cache.get(&source, 0, 0, 0).unwrap().unwrap();
+        assert_eq!(tile.metadata.etag, None);
+        assert_eq!(cache.usage().unwrap()[0].bytes, DATA.len() as u64);
+    }
+}
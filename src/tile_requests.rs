@@ -0,0 +1,150 @@
+use super::downloader::Downloader;
+use super::map_services::*;
+use super::tile_cache::TileCache;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+type TileKey = (String, u32, u32, u32);
+type InFlightMap = Mutex<HashMap<TileKey, Weak<InFlightTile>>>;
+
+/// A fetch shared by everyone who requested the tile. It runs in its own task
+/// so that it outlives any single requester, and is aborted once the last of
+/// them drops it.
+struct InFlightTile {
+    key: TileKey,
+    result: watch::Receiver<Option<Result<Vec<u8>, TileError>>>,
+    task: JoinHandle<()>,
+    in_flight: Arc<InFlightMap>,
+}
+
+impl Drop for InFlightTile {
+    fn drop(&mut self) {
+        self.task.abort();
+        let mut in_flight = self.in_flight.lock().unwrap();
+        // The tile may have been requested again since, with a new fetch
+        let is_this_fetch = in_flight
+            .get(&self.key)
+            .map_or(false, |tile| tile.strong_count() == 0);
+        if is_this_fetch {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+/// Tracks the tiles being fetched, so that a tile requested several times is
+/// only fetched once.
+#[derive(Default)]
+pub struct TileRequests {
+    in_flight: Arc<InFlightMap>,
+}
+
+impl TileRequests {
+    /// Returns the contents of a tile as `get_tile` does, joining the fetch of
+    /// the tile if one is already running. The fetch is cancelled when all the
+    /// futures waiting for it are dropped.
+    pub async fn get_tile(
+        &self,
+        source: &Arc<dyn TileSource>,
+        cache: &Arc<dyn TileCache>,
+        downloader: &Arc<Downloader>,
+        x: u32,
+        y: u32,
+        z: u32,
+    ) -> Result<Vec<u8>, TileError> {
+        let tile = self.fetch(source, cache, downloader, x, y, z);
+        let mut receiver = tile.result.clone();
+        loop {
+            if let Some(result) = &*receiver.borrow() {
+                return result.clone();
+            }
+            if receiver.changed().await.is_err() {
+                return Err(TileError::Cancelled);
+            }
+        }
+    }
+
+    fn fetch(
+        &self,
+        source: &Arc<dyn TileSource>,
+        cache: &Arc<dyn TileCache>,
+        downloader: &Arc<Downloader>,
+        x: u32,
+        y: u32,
+        z: u32,
+    ) -> Arc<InFlightTile> {
+        let key = (source.name().to_string(), x, y, z);
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(tile) = in_flight.get(&key).and_then(|tile| tile.upgrade()) {
+            return tile;
+        }
+
+        let (sender, result) = watch::channel(None);
+        let source = source.clone();
+        let cache = cache.clone();
+        let downloader = downloader.clone();
+        let task = tokio::spawn(async move {
+            let data = get_tile(&*source, &*cache, &downloader, x, y, z).await;
+            let _ = sender.send(Some(data));
+        });
+        let tile = Arc::new(InFlightTile {
+            key: key.clone(),
+            result,
+            task,
+            in_flight: self.in_flight.clone(),
+        });
+        in_flight.insert(key, Arc::downgrade(&tile));
+        tile
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::downloader::DownloadOptions;
+    use crate::mock_tile_server::*;
+    use std::time::Duration;
+
+    fn slow_jpeg() -> Vec<MockResponse> {
+        vec![MockResponse::Slow(
+            Duration::from_millis(200),
+            Box::new(MockResponse::Tile(synthetic_jpeg())),
+        )]
+    }
+
+    #[tokio::test]
+    async fn shares_the_fetch_of_a_tile() {
+        let fixture = Fixture::start("imagery", "jpeg", DownloadOptions::default()).await;
+        fixture.server.respond(1, 2, 3, slow_jpeg());
+        let requests = TileRequests::default();
+        let (source, cache, downloader) = (&fixture.source, &fixture.cache, &fixture.downloader);
+
+        let (first, second) = tokio::join!(
+            requests.get_tile(source, cache, downloader, 1, 2, 3),
+            requests.get_tile(source, cache, downloader, 1, 2, 3),
+        );
+        assert_eq!(first.unwrap(), synthetic_jpeg());
+        assert_eq!(second.unwrap(), synthetic_jpeg());
+        assert_eq!(fixture.server.requests(1, 2, 3), 1);
+        assert!(requests.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancels_the_fetch_nobody_waits_for() {
+        let fixture = Fixture::start("imagery", "jpeg", DownloadOptions::default()).await;
+        fixture.server.respond(1, 2, 3, slow_jpeg());
+        let requests = TileRequests::default();
+        let (source, cache, downloader) = (&fixture.source, &fixture.cache, &fixture.downloader);
+
+        let request = requests.get_tile(source, cache, downloader, 1, 2, 3);
+        let result = tokio::time::timeout(Duration::from_millis(50), request).await;
+        assert!(result.is_err());
+        assert!(requests.in_flight.lock().unwrap().is_empty());
+
+        // Had the fetch gone on, the tile would have been cached by then
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(fixture.server.requests(1, 2, 3), 1);
+        assert!(cache.get(&**source, 1, 2, 3).unwrap().is_none());
+    }
+}